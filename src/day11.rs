@@ -1,29 +1,126 @@
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::HashMap,
+    env,
     fmt::Display,
     fs,
+    io::{self, Write},
+    thread,
+    time::Duration,
 };
 
 use itertools::Itertools;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let s = fs::read_to_string(r#"/Users/rahulrav/rust/aoc2021/data/day11.txt"#)?;
-    println!("part1 {}", part1(State::new(&s)));
-    println!("part2 {}", part2(State::new(&s)));
+    // day11 [--input=PATH] [--toroidal] [--four] [--threshold=N] [--steps=N] [--max-steps=N]
+    // day11 --animate [--delay=MS] [--frames=N] [--from=N] [--record=PATH] ...
+    // day11 --play=PATH [--delay=MS]
+    let args = env::args().skip(1).collect_vec();
+    let flag = |name: &str| args.iter().find_map(|a| a.strip_prefix(name));
+    let delay =
+        Duration::from_millis(flag("--delay=").map_or(100, |d| d.parse().expect("invalid delay")));
+    if let Some(path) = flag("--play=") {
+        let recorded = fs::read_to_string(path)?;
+        play(recorded.split(CLEAR).filter(|f| !f.is_empty()), delay)?;
+        return Ok(());
+    }
+    let mut rules = Rules::default();
+    if args.iter().any(|a| a == "--toroidal") {
+        rules.boundary = Boundary::Toroidal;
+    }
+    if args.iter().any(|a| a == "--four") {
+        rules.neighborhood = Neighborhood::Four;
+    }
+    if let Some(t) = flag("--threshold=") {
+        rules.threshold = t.parse().expect("invalid threshold");
+    }
+    let input = flag("--input=").unwrap_or("/Users/rahulrav/rust/aoc2021/data/day11.txt");
+    let s = fs::read_to_string(input)?;
+    let record = flag("--record=");
+    if record.is_some() || args.iter().any(|a| a == "--animate") {
+        let from = flag("--from=").map_or(0, |n| n.parse().expect("invalid start frame"));
+        let count = flag("--frames=").map_or(100, |n| n.parse().expect("invalid frame count"));
+        let frames = frames(State::with_rules(&s, rules), from, count);
+        match record {
+            Some(path) => fs::write(
+                path,
+                frames.iter().map(|f| format!("{}{}", CLEAR, f)).join(""),
+            )?,
+            None => play(frames.iter().map(String::as_str), delay)?,
+        }
+        return Ok(());
+    }
+    println!("part1 {}", part1(State::with_rules(&s, rules)));
+    let max_steps =
+        flag("--max-steps=").map_or(MAX_STEPS, |m| m.parse().expect("invalid step limit"));
+    match find_cycle(State::with_rules(&s, rules), max_steps) {
+        Some(cycle) => {
+            match cycle.first_full_flash() {
+                Some(n) => println!("part2 {}", n),
+                None => println!("part2 never"),
+            }
+            println!("cycle start {} period {}", cycle.start, cycle.period);
+            let steps = flag("--steps=").map_or(1_000_000_000_000, |n| {
+                n.parse().expect("invalid step count")
+            });
+            println!(
+                "flashes after {} steps {}",
+                steps,
+                cycle.total_flashes(steps)
+            );
+        }
+        None => println!("no cycle within {} steps", max_steps),
+    }
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Boundary {
+    Bounded,
+    Toroidal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Neighborhood {
+    Four,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rules {
+    boundary: Boundary,
+    neighborhood: Neighborhood,
+    // Energy level at which an octopus flashes
+    threshold: u8,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            boundary: Boundary::Bounded,
+            neighborhood: Neighborhood::Eight,
+            threshold: 10,
+        }
+    }
+}
+
 struct State {
     octo: Vec<Vec<u8>>,
+    rules: Rules,
 }
 
 impl State {
+    #[allow(dead_code)]
     fn new(s: &str) -> Self {
+        Self::with_rules(s, Rules::default())
+    }
+
+    fn with_rules(s: &str, rules: Rules) -> Self {
+        assert!(rules.threshold > 0, "flash threshold must be positive");
         let octo = s
             .lines()
             .map(|l| l.bytes().map(|c| c - b'0').collect_vec())
             .collect_vec();
-        Self { octo }
+        Self { octo, rules }
     }
 
     fn neighbors(&self, r: usize, c: usize) -> Vec<(usize, usize)> {
@@ -31,17 +128,27 @@ impl State {
         let n_cols = self.octo[0].len() as isize;
         (-1..2)
             .cartesian_product(-1..2)
+            .filter(|&(i, j)| {
+                (i, j) != (0, 0)
+                    && (self.rules.neighborhood == Neighborhood::Eight || i == 0 || j == 0)
+            })
             .filter_map(|(i, j)| {
                 let r_ = r as isize + i;
                 let c_ = c as isize + j;
-                if r_ == r as isize && c_ == c as isize {
-                    None
-                } else if r_ >= 0 && r_ < n_rows && c_ >= 0 && c_ < n_cols {
-                    Some((r_ as usize, c_ as usize))
-                } else {
-                    None
+                match self.rules.boundary {
+                    Boundary::Bounded if r_ >= 0 && r_ < n_rows && c_ >= 0 && c_ < n_cols => {
+                        Some((r_ as usize, c_ as usize))
+                    }
+                    Boundary::Bounded => None,
+                    Boundary::Toroidal => Some((
+                        r_.rem_euclid(n_rows) as usize,
+                        c_.rem_euclid(n_cols) as usize,
+                    )),
                 }
             })
+            // Narrow tori wrap several offsets onto the same cell
+            .filter(|&p| p != (r, c))
+            .unique()
             .collect_vec()
     }
 }
@@ -59,42 +166,144 @@ impl Display for State {
 }
 
 fn step(state: &mut State) -> usize {
-    let mut to_process: VecDeque<(usize, usize)> = (0..state.octo.len())
-        .cartesian_product(0..state.octo[0].len())
-        .collect();
-    let mut to_flash = HashSet::new();
-    while !to_process.is_empty() {
-        let (i, j) = to_process.pop_front().unwrap();
-        state.octo[i][j] += 1;
-        if state.octo[i][j] == 10 {
-            to_flash.insert((i, j));
-            for (ni, nj) in state.neighbors(i, j).into_iter() {
-                to_process.push_back((ni, nj));
+    let threshold = state.rules.threshold;
+    let mut to_flash = Vec::new();
+    for (i, j) in (0..state.octo.len()).cartesian_product(0..state.octo[0].len()) {
+        // The input may already start at or above a low threshold
+        if state.octo[i][j] < threshold {
+            state.octo[i][j] += 1;
+        }
+        if state.octo[i][j] >= threshold {
+            to_flash.push((i, j));
+        }
+    }
+    // Octopuses stop charging once they reach the threshold, so each one is
+    // pushed at most once
+    let mut num_flashes = 0;
+    while let Some((i, j)) = to_flash.pop() {
+        num_flashes += 1;
+        for (ni, nj) in state.neighbors(i, j) {
+            if state.octo[ni][nj] < threshold {
+                state.octo[ni][nj] += 1;
+                if state.octo[ni][nj] == threshold {
+                    to_flash.push((ni, nj));
+                }
             }
         }
     }
-    let num_flashes = to_flash.len();
-    for (i, j) in to_flash.drain() {
-        state.octo[i][j] = 0
+    for o in state.octo.iter_mut().flatten() {
+        if *o >= threshold {
+            *o = 0;
+        }
     }
     num_flashes
 }
 
+// Moves the cursor home and clears the screen; also separates recorded frames
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+// Octopuses that just flashed are the ones back at 0, drawn bold yellow
+fn frame(state: &State, n: usize, flashes: usize) -> String {
+    let mut s = format!("step {} flashes {}\n", n, flashes);
+    for line in &state.octo {
+        for &c in line {
+            if c == 0 && n > 0 {
+                s.push_str("\x1b[1;93m0\x1b[0m");
+            } else {
+                s.push_str(&format!("\x1b[2m{}\x1b[0m", c));
+            }
+        }
+        s.push('\n');
+    }
+    s
+}
+
+// count frames starting after `from` steps
+fn frames(mut state: State, from: usize, count: usize) -> Vec<String> {
+    let mut flashes = 0;
+    for _ in 0..from {
+        flashes = step(&mut state);
+    }
+    let mut rv = Vec::with_capacity(count);
+    for n in from..from + count {
+        if n > from {
+            flashes = step(&mut state);
+        }
+        rv.push(frame(&state, n, flashes));
+    }
+    rv
+}
+
+fn play<'a>(frames: impl Iterator<Item = &'a str>, delay: Duration) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    for f in frames {
+        write!(out, "{}{}", CLEAR, f)?;
+        out.flush()?;
+        thread::sleep(delay);
+    }
+    Ok(())
+}
+
 fn part1(mut state: State) -> usize {
     (0..100).map(|_| step(&mut state)).sum()
 }
 
-fn part2(mut state: State) -> usize {
+// The grid has finitely many states, so every run ends up periodic
+const MAX_STEPS: usize = 1_000_000;
+
+struct Cycle {
+    // State after `start` steps recurs every `period` steps
+    start: usize,
+    period: usize,
+    // flashes[i] is the number of flashes on step i + 1, up to the first repeat
+    flashes: Vec<usize>,
+    size: usize,
+}
+
+impl Cycle {
+    fn total_flashes(&self, steps: u64) -> u64 {
+        let prefix = |n: usize| self.flashes[..n].iter().sum::<usize>() as u64;
+        let (start, period) = (self.start as u64, self.period as u64);
+        if steps <= start {
+            return prefix(steps as usize);
+        }
+        let cycle_sum = prefix(self.start + self.period) - prefix(self.start);
+        let (full, rest) = ((steps - start) / period, (steps - start) % period);
+        prefix(self.start) + full * cycle_sum + prefix(self.start + rest as usize)
+            - prefix(self.start)
+    }
+
+    fn first_full_flash(&self) -> Option<usize> {
+        self.flashes
+            .iter()
+            .position(|&f| f == self.size)
+            .map(|i| i + 1)
+    }
+}
+
+fn find_cycle(mut state: State, max_steps: usize) -> Option<Cycle> {
     let size = state.octo.len() * state.octo[0].len();
-    (1..)
-        .find_map(|i| {
-            if step(&mut state) == size {
-                Some(i)
-            } else {
-                None
-            }
-        })
-        .unwrap()
+    let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut flashes = Vec::new();
+    for n in 0..=max_steps {
+        let key = state.octo.concat();
+        if let Some(&start) = seen.get(&key) {
+            return Some(Cycle {
+                start,
+                period: n - start,
+                flashes,
+                size,
+            });
+        }
+        seen.insert(key, n);
+        flashes.push(step(&mut state));
+    }
+    None
+}
+
+#[allow(dead_code)]
+fn part2(state: State) -> Option<usize> {
+    find_cycle(state, MAX_STEPS)?.first_full_flash()
 }
 
 #[cfg(test)]
@@ -146,6 +355,125 @@ mod tests {
     #[test]
     fn test_part2() {
         let state = State::new(TEST_INPUT);
-        assert_eq!(195, part2(state));
+        assert_eq!(Some(195), part2(state));
+    }
+
+    #[test]
+    fn test_four_neighbors() {
+        let s = "11111
+19991
+19191
+19991
+11111";
+        let rules = Rules {
+            neighborhood: Neighborhood::Four,
+            ..Rules::default()
+        };
+        let mut state = State::with_rules(s, rules);
+        assert_eq!(8, step(&mut state));
+        assert_eq!("23332\n30003\n30603\n30003\n23332\n", state.to_string());
+    }
+
+    #[test]
+    fn test_toroidal() {
+        let rules = Rules {
+            boundary: Boundary::Toroidal,
+            ..Rules::default()
+        };
+        let state = State::with_rules(TEST_INPUT, rules);
+        assert_eq!(8, state.neighbors(0, 0).len());
+        assert!(state.neighbors(0, 0).contains(&(9, 9)));
+        // A torus has no edges, so shifting the grid shifts the result
+        let shifted = TEST_INPUT
+            .lines()
+            .cycle()
+            .skip(3)
+            .take(10)
+            .map(|l| format!("{}{}", &l[4..], &l[..4]))
+            .join("\n");
+        let mut a = State::with_rules(TEST_INPUT, rules);
+        let mut b = State::with_rules(&shifted, rules);
+        for _ in 0..20 {
+            assert_eq!(step(&mut a), step(&mut b));
+        }
+        assert_eq!(a.octo[3][4], b.octo[0][0]);
+        let narrow = State::with_rules("12\n34", rules);
+        assert_eq!(3, narrow.neighbors(0, 0).len());
+    }
+
+    #[test]
+    fn test_threshold() {
+        let rules = Rules {
+            threshold: 11,
+            ..Rules::default()
+        };
+        let mut state = State::with_rules("9", rules);
+        assert_eq!(0, step(&mut state));
+        assert_eq!(1, step(&mut state));
+        assert_eq!("0\n", state.to_string());
+        let rules = Rules {
+            threshold: 5,
+            ..Rules::default()
+        };
+        let mut state = State::with_rules("9", rules);
+        assert_eq!(1, step(&mut state));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let cycle = find_cycle(State::new(TEST_INPUT), MAX_STEPS).unwrap();
+        // All zeros after step 195, and all zeros again ten steps later
+        assert_eq!((195, 10), (cycle.start, cycle.period));
+        assert_eq!(Some(195), cycle.first_full_flash());
+        assert_eq!(204, cycle.total_flashes(10));
+        assert_eq!(1656, cycle.total_flashes(100));
+        let mut state = State::new(TEST_INPUT);
+        let expected: usize = (0..1000).map(|_| step(&mut state)).sum();
+        assert_eq!(expected as u64, cycle.total_flashes(1000));
+        let before = cycle.total_flashes(205);
+        assert_eq!(
+            before + 100 * 1_000_000_000,
+            cycle.total_flashes(205 + 10 * 1_000_000_000)
+        );
+        assert!(find_cycle(State::new(TEST_INPUT), 100).is_none());
+        // With four neighbours the grid settles into a loop that never syncs
+        let rules = Rules {
+            neighborhood: Neighborhood::Four,
+            ..Rules::default()
+        };
+        let cycle = find_cycle(State::with_rules(TEST_INPUT, rules), MAX_STEPS).unwrap();
+        assert_eq!((108, 8), (cycle.start, cycle.period));
+        assert_eq!(None, part2(State::with_rules(TEST_INPUT, rules)));
+    }
+
+    fn strip_ansi(s: &str) -> String {
+        let mut rv = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.find(|&c| c.is_ascii_alphabetic());
+            } else {
+                rv.push(c);
+            }
+        }
+        rv
+    }
+
+    #[test]
+    fn test_frames() {
+        let frames = frames(State::new(TEST_INPUT), 0, 3);
+        assert_eq!(3, frames.len());
+        assert_eq!(
+            format!("step 0 flashes 0\n{}", State::new(TEST_INPUT)),
+            strip_ansi(&frames[0])
+        );
+        assert!(!frames[0].contains("\x1b[1;93m"));
+        // Step 2 flashes 35 octopuses
+        assert!(frames[2].starts_with("step 2 flashes 35\n"));
+        assert_eq!(35, frames[2].matches("\x1b[1;93m0").count());
+        let later = super::frames(State::new(TEST_INPUT), 2, 1);
+        assert_eq!(frames[2], later[0]);
+        let synced = super::frames(State::new(TEST_INPUT), 195, 1);
+        assert_eq!(100, synced[0].matches("\x1b[1;93m0").count());
     }
 }
@@ -1,132 +1,337 @@
 aoc_main::main! {
     year 2021;
-    day12 : generator => part_1, part_2;
+    day12 : generator? => part_1, part_2;
 }
 
 mod day12 {
-    use std::collections::{HashMap, HashSet};
+    use std::{
+        collections::{HashMap, HashSet},
+        env, fmt,
+    };
 
-    #[derive(PartialEq, Eq, Hash, Debug)]
-    pub struct Cave(String);
+    #[derive(Debug)]
+    pub struct CaveSystem {
+        names: Vec<String>,
+        // Bit i is set if cave i is small
+        small: u64,
+        connections: Vec<Vec<usize>>,
+        start: usize,
+        end: usize,
+    }
 
-    impl Cave {
-        fn is_small_cave(&self) -> bool {
-            self.0.chars().next().unwrap().is_lowercase()
+    #[derive(Debug, PartialEq)]
+    pub enum CaveError {
+        Malformed {
+            line: usize,
+            text: String,
+        },
+        SelfLoop {
+            line: usize,
+            cave: String,
+        },
+        // Two adjacent big caves give infinitely many paths
+        BigCaves {
+            line: usize,
+            caves: (String, String),
+        },
+        Missing(&'static str),
+        TooManyCaves(usize),
+    }
+
+    impl fmt::Display for CaveError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                CaveError::Malformed { line, text } => {
+                    write!(f, "line {}: expected `cave-cave`, got {:?}", line, text)
+                }
+                CaveError::SelfLoop { line, cave } => {
+                    write!(f, "line {}: cave {} connects to itself", line, cave)
+                }
+                CaveError::BigCaves { line, caves } => write!(
+                    f,
+                    "line {}: big caves {} and {} are adjacent, paths are infinite",
+                    line, caves.0, caves.1
+                ),
+                CaveError::Missing(cave) => write!(f, "no {} cave", cave),
+                CaveError::TooManyCaves(n) => write!(f, "{} caves, at most 64 supported", n),
+            }
         }
     }
 
-    #[derive(Debug)]
-    pub struct CaveSystem {
-        caves: HashSet<Cave>,
-        connections: HashMap<Cave, Vec<Cave>>,
+    impl std::error::Error for CaveError {}
+
+    fn is_big(name: &str) -> bool {
+        name.chars().next().unwrap().is_uppercase()
     }
 
     impl CaveSystem {
-        fn new(s: &str) -> Self {
-            let mut caves: HashSet<Cave> = HashSet::new();
-            let mut connections: HashMap<Cave, Vec<Cave>> = HashMap::new();
-            for l in s.lines() {
-                let mut tokens = l.split('-');
-                let c0 = String::from(tokens.next().unwrap());
-                let c1 = String::from(tokens.next().unwrap());
-                caves.insert(Cave(c0.clone()));
-                caves.insert(Cave(c1.clone()));
-                let entry = connections.entry(Cave(c0.clone())).or_insert(Vec::new());
-                entry.push(Cave(c1.clone()));
-                let entry = connections.entry(Cave(c1.clone())).or_insert(Vec::new());
-                entry.push(Cave(c0.clone()));
-            }
-            Self { caves, connections }
-        }
-    }
-
-    fn paths2<'a>(
-        cave: &'a Cave,
-        cs: &'a CaveSystem,
-        visited: &mut HashMap<&'a Cave, usize>,
-        path: &mut Vec<&'a Cave>,
-        s_cave_used_twice: bool,
+        fn new(s: &str) -> Result<Self, CaveError> {
+            let mut ids: HashMap<&str, usize> = HashMap::new();
+            let mut edges = Vec::new();
+            for (i, l) in s.lines().enumerate() {
+                let line = i + 1;
+                let l = l.trim();
+                if l.is_empty() {
+                    continue;
+                }
+                let tokens = l.split('-').collect::<Vec<_>>();
+                if tokens.len() != 2
+                    || tokens
+                        .iter()
+                        .any(|t| t.is_empty() || !t.chars().all(|c| c.is_ascii_alphabetic()))
+                {
+                    return Err(CaveError::Malformed {
+                        line,
+                        text: String::from(l),
+                    });
+                }
+                let (c0, c1) = (tokens[0], tokens[1]);
+                if c0 == c1 {
+                    return Err(CaveError::SelfLoop {
+                        line,
+                        cave: String::from(c0),
+                    });
+                }
+                if is_big(c0) && is_big(c1) {
+                    return Err(CaveError::BigCaves {
+                        line,
+                        caves: (String::from(c0), String::from(c1)),
+                    });
+                }
+                for c in [c0, c1] {
+                    let next_id = ids.len();
+                    ids.entry(c).or_insert(next_id);
+                }
+                edges.push((ids[c0], ids[c1]));
+            }
+            let start = *ids.get("start").ok_or(CaveError::Missing("start"))?;
+            let end = *ids.get("end").ok_or(CaveError::Missing("end"))?;
+            if ids.len() > 64 {
+                return Err(CaveError::TooManyCaves(ids.len()));
+            }
+
+            let mut names = vec![String::new(); ids.len()];
+            for (name, &i) in &ids {
+                names[i] = String::from(*name);
+            }
+            let mut connections = vec![Vec::new(); ids.len()];
+            for (c0, c1) in edges {
+                connections[c0].push(c1);
+                connections[c1].push(c0);
+            }
+            let small = names
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| !is_big(n))
+                .fold(0, |acc, (i, _)| acc | 1 << i);
+            Ok(Self {
+                names,
+                small,
+                connections,
+                start,
+                end,
+            })
+        }
+
+        fn is_small_cave(&self, cave: usize) -> bool {
+            self.small & (1 << cave) != 0
+        }
+    }
+
+    // Paths from cave to end where small caves may be revisited `revisits`
+    // more times in total, e.g. with 2 one cave three times or two caves twice
+    fn count_paths(
+        cave: usize,
+        cs: &CaveSystem,
+        mut visited: u64,
+        revisits: usize,
+        cache: &mut HashMap<(usize, u64, usize), usize>,
     ) -> usize {
-        // println!("{} {:?} {}", cave.0, visited, s_cave_used_twice);
-        if cave.0 == "end" {
-            // for c in path {
-            //     print!("{}-", c.0);
-            // }
-            // println!("end");
+        if cave == cs.end {
             return 1;
         }
-        path.push(cave);
-        if cave.is_small_cave() {
-            let entry = visited.entry(cave).or_insert(0);
-            *entry += 1;
+        if cs.is_small_cave(cave) {
+            visited |= 1 << cave;
+        }
+        if let Some(&rv) = cache.get(&(cave, visited, revisits)) {
+            return rv;
         }
         let mut rv = 0;
-        for con in cs.connections.get(cave).unwrap() {
-            if con.0 == "start" {
+        for &con in &cs.connections[cave] {
+            if con == cs.start {
                 continue;
             }
-            if visited.contains_key(con) {
-                if s_cave_used_twice {
+            if visited & (1 << con) == 0 {
+                rv += count_paths(con, cs, visited, revisits, cache);
+            } else if revisits > 0 {
+                rv += count_paths(con, cs, visited, revisits - 1, cache);
+            }
+        }
+        cache.insert((cave, visited, revisits), rv);
+        rv
+    }
+
+    pub fn num_paths(cs: &CaveSystem, revisits: usize) -> usize {
+        count_paths(cs.start, cs, 0, revisits, &mut HashMap::new())
+    }
+
+    // Depth first walk over neighbors sorted by name, so paths come out in the
+    // same order the puzzle lists them
+    pub struct Paths<'a> {
+        cs: &'a CaveSystem,
+        neighbors: Vec<Vec<usize>>,
+        // (cave, index of the next neighbor to try)
+        stack: Vec<(usize, usize)>,
+        visits: Vec<usize>,
+        revisits: usize,
+    }
+
+    impl<'a> Paths<'a> {
+        pub fn new(cs: &'a CaveSystem, revisits: usize) -> Self {
+            let neighbors = cs
+                .connections
+                .iter()
+                .map(|cons| {
+                    let mut cons = cons.clone();
+                    cons.sort_by_key(|&c| &cs.names[c]);
+                    cons
+                })
+                .collect();
+            let mut visits = vec![0; cs.names.len()];
+            visits[cs.start] = 1;
+            Self {
+                cs,
+                neighbors,
+                stack: vec![(cs.start, 0)],
+                visits,
+                revisits,
+            }
+        }
+    }
+
+    impl<'a> Iterator for Paths<'a> {
+        type Item = Vec<&'a str>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let cs = self.cs;
+            loop {
+                let (cave, next) = self.stack.last_mut()?;
+                let cave = *cave;
+                if *next == self.neighbors[cave].len() {
+                    self.stack.pop();
+                    if cs.is_small_cave(cave) {
+                        self.visits[cave] -= 1;
+                        if self.visits[cave] > 0 {
+                            self.revisits += 1;
+                        }
+                    }
                     continue;
-                } else {
-                    // Let's use this small cave twice
-                    rv += paths2(con, cs, visited, path, true);
                 }
-            } else {
-                rv += paths2(con, cs, visited, path, s_cave_used_twice);
+                let con = self.neighbors[cave][*next];
+                *next += 1;
+                if con == cs.start {
+                    continue;
+                }
+                if con == cs.end {
+                    let mut path = self
+                        .stack
+                        .iter()
+                        .map(|&(c, _)| cs.names[c].as_str())
+                        .collect::<Vec<_>>();
+                    path.push(&cs.names[con]);
+                    return Some(path);
+                }
+                if cs.is_small_cave(con) {
+                    if self.visits[con] > 0 {
+                        if self.revisits == 0 {
+                            continue;
+                        }
+                        self.revisits -= 1;
+                    }
+                    self.visits[con] += 1;
+                }
+                self.stack.push((con, 0));
             }
         }
-        path.pop();
-        if cave.is_small_cave() {
-            let ref_count = visited.get_mut(cave).unwrap();
-            *ref_count -= 1;
-            if *ref_count == 0 {
-                visited.remove(cave);
-            }
+    }
+
+    // One path per line, e.g. start,A,b,A,c,A,end
+    pub fn list_paths(cs: &CaveSystem, revisits: usize) -> String {
+        Paths::new(cs, revisits)
+            .map(|p| p.join(",") + "\n")
+            .collect()
+    }
+
+    // Set DAY12_LIST_PATHS to print every path along with the count
+    fn maybe_list(cs: &CaveSystem, revisits: usize) {
+        if env::var_os("DAY12_LIST_PATHS").is_some() {
+            print!("{}", list_paths(cs, revisits));
         }
-        rv
     }
 
-    fn paths<'a>(
-        cave: &'a Cave,
-        cs: &'a CaveSystem,
-        visited: &mut HashSet<&'a Cave>,
-        path: &mut Vec<&'a Cave>,
-    ) -> usize {
-        if cave.0 == "end" {
-            // for c in path {
-            //     print!("{}-", c.0);
-            // }
-            // println!("end");
-            return 1;
+    // GraphViz graph with big caves as boxes and small caves as circles. With
+    // counts, each edge is labelled with the number of part 1 paths using it.
+    pub fn to_dot(cs: &CaveSystem, counts: bool) -> String {
+        let mut edge_counts: HashMap<(&str, &str), usize> = HashMap::new();
+        if counts {
+            for path in Paths::new(cs, 0) {
+                let edges: HashSet<(&str, &str)> = path
+                    .windows(2)
+                    .map(|w| (w[0].min(w[1]), w[0].max(w[1])))
+                    .collect();
+                for e in edges {
+                    *edge_counts.entry(e).or_insert(0) += 1;
+                }
+            }
         }
-        path.push(cave);
-        if cave.is_small_cave() {
-            visited.insert(cave);
+
+        let mut dot = String::from("graph caves {\n");
+        for (i, name) in cs.names.iter().enumerate() {
+            let attrs = if i == cs.start {
+                "shape=doublecircle, style=filled, fillcolor=palegreen"
+            } else if i == cs.end {
+                "shape=doublecircle, style=filled, fillcolor=lightpink"
+            } else if cs.is_small_cave(i) {
+                "shape=circle"
+            } else {
+                "shape=box, style=filled, fillcolor=lightblue"
+            };
+            dot += &format!("    \"{}\" [{}];\n", name, attrs);
         }
-        let mut rv = 0;
-        for con in cs.connections.get(cave).unwrap() {
-            if con.is_small_cave() && visited.contains(con) {
-                continue;
+        for (c0, cons) in cs.connections.iter().enumerate() {
+            for &c1 in cons.iter().filter(|&&c1| c0 <= c1) {
+                let (n0, n1) = (cs.names[c0].as_str(), cs.names[c1].as_str());
+                dot += &format!("    \"{}\" -- \"{}\"", n0, n1);
+                if counts {
+                    let count = edge_counts.get(&(n0.min(n1), n0.max(n1)));
+                    dot += &format!(" [label=\"{}\"]", count.unwrap_or(&0));
+                }
+                dot += ";\n";
             }
-            rv += paths(con, cs, visited, path);
         }
-        path.pop();
-        visited.remove(cave);
-        rv
+        dot += "}\n";
+        dot
+    }
+
+    // Set DAY12_DOT to print the cave system as a GraphViz graph
+    fn maybe_dot(cs: &CaveSystem) {
+        if env::var_os("DAY12_DOT").is_some() {
+            print!("{}", to_dot(cs, true));
+        }
     }
 
     pub fn part_1(cs: &CaveSystem) -> usize {
-        let start = cs.caves.get(&Cave(String::from("start"))).unwrap();
-        paths(start, cs, &mut HashSet::new(), &mut Vec::new())
+        maybe_dot(cs);
+        maybe_list(cs, 0);
+        num_paths(cs, 0)
     }
 
     pub fn part_2(cs: &CaveSystem) -> usize {
-        let start = cs.caves.get(&Cave(String::from("start"))).unwrap();
-        paths2(start, cs, &mut HashMap::new(), &mut Vec::new(), false)
+        maybe_list(cs, 1);
+        num_paths(cs, 1)
     }
 
-    pub fn generator(input: &str) -> CaveSystem {
+    pub fn generator(input: &str) -> Result<CaveSystem, CaveError> {
         CaveSystem::new(input)
     }
 
@@ -144,12 +349,12 @@ b-end";
 
         #[test]
         fn test_parse() {
-            dbg!(CaveSystem::new(TEST_INPUT));
+            dbg!(CaveSystem::new(TEST_INPUT).unwrap());
         }
 
         #[test]
         fn test_part1() {
-            assert_eq!(part_1(&CaveSystem::new(TEST_INPUT)), 10);
+            assert_eq!(part_1(&CaveSystem::new(TEST_INPUT).unwrap()), 10);
         }
 
         #[test]
@@ -164,12 +369,191 @@ HN-end
 kj-sa
 kj-HN
 kj-dc";
-            assert_eq!(part_1(&CaveSystem::new(s)), 19);
+            assert_eq!(part_1(&CaveSystem::new(s).unwrap()), 19);
+        }
+
+        const LARGER_INPUT: &str = "fs-end
+he-DX
+fs-he
+start-DX
+pj-DX
+end-zg
+zg-sl
+zg-pj
+pj-he
+RW-he
+fs-DX
+pj-RW
+zg-RW
+start-pj
+he-WI
+zg-he
+pj-fs
+start-RW";
+
+        #[test]
+        fn test_larger() {
+            let cs = CaveSystem::new(LARGER_INPUT).unwrap();
+            assert_eq!(part_1(&cs), 226);
+            assert_eq!(part_2(&cs), 3509);
+        }
+
+        // Counts visits per cave explicitly
+        fn brute_force(
+            cave: usize,
+            cs: &CaveSystem,
+            visits: &mut Vec<usize>,
+            revisits: usize,
+        ) -> usize {
+            if cave == cs.end {
+                return 1;
+            }
+            if cs.is_small_cave(cave) {
+                visits[cave] += 1;
+            }
+            let used: usize = visits.iter().map(|v| v.saturating_sub(1)).sum();
+            let mut rv = 0;
+            for &con in &cs.connections[cave] {
+                if con == cs.start || (visits[con] > 0 && used == revisits) {
+                    continue;
+                }
+                rv += brute_force(con, cs, visits, revisits);
+            }
+            if cs.is_small_cave(cave) {
+                visits[cave] -= 1;
+            }
+            rv
+        }
+
+        #[test]
+        fn test_revisits() {
+            for input in [TEST_INPUT, LARGER_INPUT] {
+                let cs = CaveSystem::new(input).unwrap();
+                for revisits in 0..4 {
+                    let mut visits = vec![0; cs.names.len()];
+                    assert_eq!(
+                        num_paths(&cs, revisits),
+                        brute_force(cs.start, &cs, &mut visits, revisits)
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn test_list_paths() {
+            let expected = "start,A,b,A,c,A,end
+start,A,b,A,end
+start,A,b,end
+start,A,c,A,b,A,end
+start,A,c,A,b,end
+start,A,c,A,end
+start,A,end
+start,b,A,c,A,end
+start,b,A,end
+start,b,end
+";
+            assert_eq!(
+                list_paths(&CaveSystem::new(TEST_INPUT).unwrap(), 0),
+                expected
+            );
+        }
+
+        #[test]
+        fn test_paths_iter() {
+            for input in [TEST_INPUT, LARGER_INPUT] {
+                let cs = CaveSystem::new(input).unwrap();
+                for revisits in 0..3 {
+                    let paths = Paths::new(&cs, revisits).collect::<Vec<_>>();
+                    assert_eq!(paths.len(), num_paths(&cs, revisits));
+                    assert!(paths.windows(2).all(|w| w[0] < w[1]));
+                    assert!(paths
+                        .iter()
+                        .all(|p| p[0] == "start" && p[p.len() - 1] == "end"));
+                }
+            }
+        }
+
+        #[test]
+        fn test_to_dot() {
+            let cs = CaveSystem::new(TEST_INPUT).unwrap();
+            let dot = to_dot(&cs, false);
+            assert!(dot.starts_with("graph caves {\n"));
+            assert!(dot.contains("\"A\" [shape=box"));
+            assert!(dot.contains("\"b\" [shape=circle]"));
+            assert!(dot.contains("\"start\" [shape=doublecircle"));
+            assert!(dot.contains("\"end\" [shape=doublecircle"));
+            assert_eq!(dot.matches(" -- ").count(), 7);
+            assert!(!dot.contains("label"));
+
+            let dot = to_dot(&cs, true);
+            assert!(dot.contains("\"start\" -- \"A\" [label=\"7\"];"));
+            assert!(dot.contains("\"start\" -- \"b\" [label=\"3\"];"));
+            assert!(dot.contains("\"A\" -- \"c\" [label=\"5\"];"));
+            assert!(dot.contains("\"b\" -- \"d\" [label=\"0\"];"));
+        }
+
+        #[test]
+        fn test_validation() {
+            assert_eq!(
+                CaveSystem::new("start-A\nA-B\nB-end").unwrap_err(),
+                CaveError::BigCaves {
+                    line: 2,
+                    caves: (String::from("A"), String::from("B"))
+                }
+            );
+            assert_eq!(
+                CaveSystem::new("start-A\nA-end\nb-b").unwrap_err(),
+                CaveError::SelfLoop {
+                    line: 3,
+                    cave: String::from("b")
+                }
+            );
+            for bad in ["start-A-end", "start", "start-", "start-a b", "st@rt-end"] {
+                assert_eq!(
+                    CaveSystem::new(bad).unwrap_err(),
+                    CaveError::Malformed {
+                        line: 1,
+                        text: String::from(bad)
+                    }
+                );
+            }
+            assert_eq!(
+                CaveSystem::new("A-b\nb-end").unwrap_err(),
+                CaveError::Missing("start")
+            );
+            assert_eq!(
+                CaveSystem::new("start-A\nA-b").unwrap_err(),
+                CaveError::Missing("end")
+            );
+            let many = (0..70)
+                .map(|i| {
+                    format!(
+                        "start-{}{}\n",
+                        (b'a' + i / 26) as char,
+                        (b'a' + i % 26) as char
+                    )
+                })
+                .collect::<String>()
+                + "start-end";
+            assert_eq!(
+                CaveSystem::new(&many).unwrap_err(),
+                CaveError::TooManyCaves(72)
+            );
+            assert_eq!(
+                CaveError::BigCaves {
+                    line: 2,
+                    caves: (String::from("A"), String::from("B"))
+                }
+                .to_string(),
+                "line 2: big caves A and B are adjacent, paths are infinite"
+            );
+            // Trailing newlines are fine
+            assert!(CaveSystem::new("start-A\nA-end\n\n").is_ok());
         }
 
         #[test]
         fn test_part2() {
-            assert_eq!(part_2(&CaveSystem::new(TEST_INPUT)), 36);
+            assert_eq!(part_2(&CaveSystem::new(TEST_INPUT).unwrap()), 36);
         }
 
         #[test]
@@ -178,7 +562,7 @@ kj-dc";
 A-b
 b-c
 c-end";
-            assert_eq!(part_2(&CaveSystem::new(s)), 2);
+            assert_eq!(part_2(&CaveSystem::new(s).unwrap()), 2);
         }
     }
 }
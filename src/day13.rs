@@ -1,99 +1,439 @@
 aoc_main::main! {
     year 2021;
-    day13 : generator => part_1, part_2;
+    day13 : generator? => part_1?, part_2?;
 }
 
 mod day13 {
-    use std::collections::HashSet;
+    use std::{collections::HashSet, env, fmt, fs};
 
     use itertools::Itertools;
 
     #[derive(Debug)]
-    enum Fold {
+    pub enum Fold {
         X(usize),
         Y(usize),
     }
 
+    impl fmt::Display for Fold {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Fold::X(val) => write!(f, "fold along x={}", val),
+                Fold::Y(val) => write!(f, "fold along y={}", val),
+            }
+        }
+    }
+
     #[derive(Debug)]
     pub struct Input {
         dots: Vec<(usize, usize)>,
         folds: Vec<Fold>,
     }
 
-    pub fn generator(input: &str) -> Input {
-        let mut split = input.split("\n\n");
-        let dots = split
-            .next()
-            .unwrap()
-            .lines()
-            .filter_map(|l| l.split(',').map(|c| c.parse().unwrap()).collect_tuple())
-            .collect_vec();
+    // Folds may appear among the dots and spaces around tokens are ignored
+    pub fn generator(input: &str) -> Result<Input, String> {
+        let mut dots = Vec::new();
         let mut folds = Vec::new();
-        let const_len = "fold along ".len();
-        for l in split.next().unwrap().lines() {
-            let s = &l[const_len..];
-            let mut tokens = s.split('=');
-            let axis = tokens.next().unwrap();
-            let val = tokens.next().unwrap().parse().unwrap();
-            let fold = match axis {
-                "x" => Fold::X(val),
-                "y" => Fold::Y(val),
-                _ => unreachable!(),
+        for (i, l) in input.lines().enumerate() {
+            let l = l.trim();
+            let err = || format!("line {}: can't parse {:?}", i + 1, l);
+            if l.is_empty() {
+                continue;
+            }
+            if let Some(rest) = l.strip_prefix("fold") {
+                let rest = rest.trim_start().strip_prefix("along").ok_or_else(err)?;
+                let (axis, val) = rest.split('=').collect_tuple().ok_or_else(err)?;
+                let val = val.trim().parse().map_err(|_| err())?;
+                folds.push(match axis.trim() {
+                    "x" => Fold::X(val),
+                    "y" => Fold::Y(val),
+                    _ => return Err(err()),
+                });
+            } else {
+                let dot = l
+                    .split(',')
+                    .map(|c| c.trim().parse())
+                    .collect_tuple()
+                    .ok_or_else(err)?;
+                match dot {
+                    (Ok(x), Ok(y)) => dots.push((x, y)),
+                    _ => return Err(err()),
+                }
+            }
+        }
+        Ok(Input { dots, folds })
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Paper {
+        pub dots: HashSet<(usize, usize)>,
+        pub width: usize,
+        pub height: usize,
+    }
+
+    impl Paper {
+        pub fn new(dots: &[(usize, usize)]) -> Self {
+            Self {
+                dots: dots.iter().cloned().collect(),
+                width: dots.iter().map(|&(x, _)| x + 1).max().unwrap_or(0),
+                height: dots.iter().map(|&(_, y)| y + 1).max().unwrap_or(0),
+            }
+        }
+
+        // When the folded half is the larger one everything is shifted so the
+        // folded half's far edge lands on 0
+        pub fn fold(&mut self, fold: &Fold) -> Result<(), String> {
+            let (line, size) = match fold {
+                Fold::X(val) => (*val, self.width),
+                Fold::Y(val) => (*val, self.height),
             };
-            folds.push(fold);
+            let coord = |&(x, y): &(usize, usize)| match fold {
+                Fold::X(_) => x,
+                Fold::Y(_) => y,
+            };
+            if let Some((x, y)) = self.dots.iter().filter(|d| coord(d) == line).min() {
+                return Err(format!("{}: dot {},{} lies on the fold line", fold, x, y));
+            }
+            let far = size.saturating_sub(line + 1);
+            let shift = far.saturating_sub(line);
+            let transform_fn = |c: usize| {
+                if c < line {
+                    c + shift
+                } else {
+                    2 * line + shift - c
+                }
+            };
+            self.dots = self
+                .dots
+                .iter()
+                .map(|&(x, y)| match fold {
+                    Fold::X(_) => (transform_fn(x), y),
+                    Fold::Y(_) => (x, transform_fn(y)),
+                })
+                .collect();
+            match fold {
+                Fold::X(_) => self.width = line + shift,
+                Fold::Y(_) => self.height = line + shift,
+            }
+            Ok(())
+        }
+
+        // Draws the next fold line the way the puzzle does
+        fn draw(&self, next: Option<&Fold>) -> String {
+            let mut s = String::new();
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    s.push(match next {
+                        Some(Fold::X(val)) if x == *val => '|',
+                        Some(Fold::Y(val)) if y == *val => '-',
+                        _ if self.dots.contains(&(x, y)) => '#',
+                        _ => '.',
+                    });
+                }
+                s.push('\n');
+            }
+            s
+        }
+    }
+
+    // The paper before any fold and after each one
+    pub fn snapshots(input: &Input) -> Result<Vec<Paper>, String> {
+        let mut paper = Paper::new(&input.dots);
+        let mut rv = vec![paper.clone()];
+        for f in &input.folds {
+            paper.fold(f)?;
+            rv.push(paper.clone());
         }
-        Input { dots, folds }
+        Ok(rv)
     }
 
-    fn fold(fold: &Fold, dot_map: &mut HashSet<(usize, usize)>) {
-        let dots = dot_map
+    pub fn frames(input: &Input) -> Result<Vec<String>, String> {
+        Ok(snapshots(input)?
             .iter()
-            .filter(|(x, y)| match fold {
-                Fold::X(val) => x > val,
-                Fold::Y(val) => y > val,
+            .enumerate()
+            .map(|(i, paper)| {
+                let title = match i {
+                    0 => String::from("initial"),
+                    _ => format!("after {}", input.folds[i - 1]),
+                };
+                format!("{}\n{}", title, paper.draw(input.folds.get(i)))
             })
-            .cloned()
-            .collect_vec();
-        let transform_fn = |(x, y)| match fold {
-            Fold::X(val) => (x - 2 * (x - val), y),
-            Fold::Y(val) => (x, y - 2 * (y - val)),
-        };
-        for (x, y) in dots.into_iter() {
-            dot_map.remove(&(x, y));
-            dot_map.insert(transform_fn((x, y)));
+            .collect())
+    }
+
+    // Set DAY13_FRAMES to a path to write every fold step there
+    fn maybe_write_frames(input: &Input) -> Result<(), String> {
+        if let Some(path) = env::var_os("DAY13_FRAMES") {
+            fs::write(path, frames(input)?.join("\n")).map_err(|e| e.to_string())?;
         }
+        Ok(())
     }
 
-    pub fn part_1(input: &Input) -> usize {
-        let mut dot_map = input.dots.iter().cloned().collect();
-        fold(&input.folds[0], &mut dot_map);
-        dot_map.len()
+    // Same folds as Paper on one bit per cell, each row packed into u64 words
+    #[derive(Debug)]
+    pub struct BitPaper {
+        pub width: usize,
+        pub height: usize,
+        row_words: usize,
+        bits: Vec<u64>,
     }
 
-    pub fn part_2(input: &Input) -> usize {
-        let mut dot_map = input.dots.iter().cloned().collect();
-        for f in &input.folds {
-            fold(f, &mut dot_map);
+    impl BitPaper {
+        pub fn new(dots: &[(usize, usize)]) -> Self {
+            let width = dots.iter().map(|&(x, _)| x + 1).max().unwrap_or(0);
+            let height = dots.iter().map(|&(_, y)| y + 1).max().unwrap_or(0);
+            let row_words = width.div_ceil(64);
+            let mut bits = vec![0; row_words * height];
+            for &(x, y) in dots {
+                bits[y * row_words + x / 64] |= 1 << (x % 64);
+            }
+            Self {
+                width,
+                height,
+                row_words,
+                bits,
+            }
         }
-        let max_col = dot_map.iter().map(|&(x, _)| x).max().unwrap();
-        let max_row = dot_map.iter().map(|&(_, y)| y).max().unwrap();
+
+        fn row(&self, y: usize) -> &[u64] {
+            &self.bits[y * self.row_words..(y + 1) * self.row_words]
+        }
+
+        pub fn count(&self) -> usize {
+            self.bits.iter().map(|w| w.count_ones() as usize).sum()
+        }
+
+        pub fn dots(&self) -> HashSet<(usize, usize)> {
+            let mut rv = HashSet::new();
+            for y in 0..self.height {
+                for (i, &w) in self.row(y).iter().enumerate() {
+                    let mut w = w;
+                    while w != 0 {
+                        rv.insert((i * 64 + w.trailing_zeros() as usize, y));
+                        w &= w - 1;
+                    }
+                }
+            }
+            rv
+        }
+
+        fn dot_on_line(&self, fold: &Fold) -> Option<(usize, usize)> {
+            match *fold {
+                Fold::X(line) if line < self.width => (0..self.height)
+                    .find(|&y| self.row(y)[line / 64] & (1 << (line % 64)) != 0)
+                    .map(|y| (line, y)),
+                Fold::Y(line) if line < self.height => {
+                    let row = self.row(line);
+                    row.iter()
+                        .position(|&w| w != 0)
+                        .map(|i| (i * 64 + row[i].trailing_zeros() as usize, line))
+                }
+                _ => None,
+            }
+        }
+
+        pub fn fold(&mut self, fold: &Fold) -> Result<(), String> {
+            if let Some((x, y)) = self.dot_on_line(fold) {
+                return Err(format!("{}: dot {},{} lies on the fold line", fold, x, y));
+            }
+            match *fold {
+                Fold::X(line) => self.fold_x(line),
+                Fold::Y(line) => self.fold_y(line),
+            }
+            Ok(())
+        }
+
+        // Rows in the folded half are ORed into their mirror rows. When the
+        // folded half is the larger one the rows are reversed first, which
+        // turns it into the kept half of a fold at its own length
+        fn fold_y(&mut self, line: usize) {
+            let far = self.height.saturating_sub(line + 1);
+            let line = if far > line {
+                let rw = self.row_words;
+                for y in 0..self.height / 2 {
+                    let (lo, hi) = self.bits.split_at_mut((self.height - 1 - y) * rw);
+                    lo[y * rw..(y + 1) * rw].swap_with_slice(&mut hi[..rw]);
+                }
+                far
+            } else {
+                line
+            };
+            for y in line + 1..self.height {
+                let (lo, hi) = self.bits.split_at_mut(y * self.row_words);
+                let dest = 2 * line - y;
+                let dest = &mut lo[dest * self.row_words..(dest + 1) * self.row_words];
+                for (d, s) in dest.iter_mut().zip(&hi[..self.row_words]) {
+                    *d |= s;
+                }
+            }
+            self.height = line;
+            self.bits.resize(self.height * self.row_words, 0);
+        }
+
+        // Each set word of a row is split at the fold line, the kept bits are
+        // shifted and the folded bits bit reversed into a scratch row, which
+        // is then packed in place. Rows only get longer for a fold past the
+        // edge, and then they are packed from the last row back
+        fn fold_x(&mut self, line: usize) {
+            let far = self.width.saturating_sub(line + 1);
+            let shift = far.saturating_sub(line);
+            let width = line + shift;
+            let row_words = width.div_ceil(64);
+            let mirror = (2 * line + shift) as isize;
+            let grow = row_words > self.row_words;
+            if grow {
+                self.bits.resize(self.height * row_words, 0);
+            }
+            let mut scratch = vec![0; row_words];
+            for y in 0..self.height {
+                let y = if grow { self.height - 1 - y } else { y };
+                scratch.fill(0);
+                for (i, &w) in self.row(y).iter().enumerate().filter(|(_, &w)| w != 0) {
+                    let start = i * 64;
+                    let kept = match line.saturating_sub(start) {
+                        0 => 0,
+                        n if n < 64 => w & ((1 << n) - 1),
+                        _ => w,
+                    };
+                    or_at(&mut scratch, kept, (start + shift) as isize);
+                    let folded = w & !kept;
+                    or_at(
+                        &mut scratch,
+                        folded.reverse_bits(),
+                        mirror - start as isize - 63,
+                    );
+                }
+                self.bits[y * row_words..(y + 1) * row_words].copy_from_slice(&scratch);
+            }
+            self.width = width;
+            self.row_words = row_words;
+            self.bits.truncate(self.height * row_words);
+        }
+    }
+
+    // ORs a word into dest starting at bit offset, dropping bits outside dest
+    fn or_at(dest: &mut [u64], w: u64, offset: isize) {
+        if w == 0 || offset <= -64 {
+            return;
+        }
+        if offset < 0 {
+            if let Some(d) = dest.first_mut() {
+                *d |= w >> -offset;
+            }
+            return;
+        }
+        let (i, off) = (offset as usize / 64, offset as usize % 64);
+        if let Some(d) = dest.get_mut(i) {
+            *d |= w << off;
+        }
+        if off != 0 {
+            if let Some(d) = dest.get_mut(i + 1) {
+                *d |= w >> (64 - off);
+            }
+        }
+    }
+
+    pub fn part_1(input: &Input) -> Result<usize, String> {
+        let mut paper = BitPaper::new(&input.dots);
+        paper.fold(input.folds.first().ok_or("no folds")?)?;
+        Ok(paper.count())
+    }
+
+    // The 4x6 capital letters AoC draws, letters are 5 columns apart
+    const FONT: [(char, [&str; 6]); 17] = [
+        ('A', [".##.", "#..#", "#..#", "####", "#..#", "#..#"]),
+        ('B', ["###.", "#..#", "###.", "#..#", "#..#", "###."]),
+        ('C', [".##.", "#..#", "#...", "#...", "#..#", ".##."]),
+        ('E', ["####", "#...", "###.", "#...", "#...", "####"]),
+        ('F', ["####", "#...", "###.", "#...", "#...", "#..."]),
+        ('G', [".##.", "#..#", "#...", "#.##", "#..#", ".###"]),
+        ('H', ["#..#", "#..#", "####", "#..#", "#..#", "#..#"]),
+        ('I', [".###", "..#.", "..#.", "..#.", "..#.", ".###"]),
+        ('J', ["..##", "...#", "...#", "...#", "#..#", ".##."]),
+        ('K', ["#..#", "#.#.", "##..", "#.#.", "#.#.", "#..#"]),
+        ('L', ["#...", "#...", "#...", "#...", "#...", "####"]),
+        ('O', [".##.", "#..#", "#..#", "#..#", "#..#", ".##."]),
+        ('P', ["###.", "#..#", "#..#", "###.", "#...", "#..."]),
+        ('R', ["###.", "#..#", "#..#", "###.", "#.#.", "#..#"]),
+        ('S', [".###", "#...", "#...", ".##.", "...#", "###."]),
+        ('U', ["#..#", "#..#", "#..#", "#..#", "#..#", ".##."]),
+        ('Z', ["####", "...#", "..#.", ".#..", "#...", "####"]),
+    ];
+
+    fn render(dot_map: &HashSet<(usize, usize)>) -> String {
+        let max_col = dot_map.iter().map(|&(x, _)| x).max().unwrap_or(0);
+        let max_row = dot_map.iter().map(|&(_, y)| y).max().unwrap_or(0);
+        let mut s = String::new();
         for r in 0..=max_row {
             for c in 0..=max_col {
                 if dot_map.contains(&(c, r)) {
-                    print!("#");
+                    s.push('#');
                 } else {
-                    print!(" ");
+                    s.push('.');
                 }
             }
-            println!();
+            s.push('\n');
         }
-        0
+        s
+    }
+
+    pub fn ocr(dot_map: &HashSet<(usize, usize)>) -> Result<String, String> {
+        let max_col = dot_map.iter().map(|&(x, _)| x).max().ok_or("no dots")?;
+        let max_row = dot_map.iter().map(|&(_, y)| y).max().unwrap();
+        if max_row >= 6 {
+            return Err(format!(
+                "dots span {} rows, letters are 6\n{}",
+                max_row + 1,
+                render(dot_map)
+            ));
+        }
+        (0..=max_col / 5)
+            .map(|i| {
+                let glyph = (0..6)
+                    .map(|r| {
+                        (0..5)
+                            .map(|c| {
+                                if dot_map.contains(&(i * 5 + c, r)) {
+                                    '#'
+                                } else {
+                                    '.'
+                                }
+                            })
+                            .collect::<String>()
+                    })
+                    .collect_vec();
+                // The fifth column is the gap between letters
+                FONT.iter()
+                    .find(|(_, rows)| {
+                        rows.iter()
+                            .zip(&glyph)
+                            .all(|(f, g)| g == &format!("{}.", f))
+                    })
+                    .map(|(c, _)| *c)
+                    .ok_or_else(|| {
+                        format!(
+                            "unrecognized glyph at letter {}\n{}",
+                            i + 1,
+                            glyph.join("\n")
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    pub fn part_2(input: &Input) -> Result<String, String> {
+        maybe_write_frames(input)?;
+        let mut paper = BitPaper::new(&input.dots);
+        for f in &input.folds {
+            paper.fold(f)?;
+        }
+        ocr(&paper.dots())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::day13::*;
+    use std::collections::HashSet;
 
     const TEST_INPUT: &str = "6,10
 0,14
@@ -120,12 +460,207 @@ fold along x=5";
     #[test]
     #[ignore]
     fn test_generator() {
-        println!("{:?}", generator(TEST_INPUT));
+        println!("{:?}", generator(TEST_INPUT).unwrap());
+    }
+
+    fn dots(rows: &[&str]) -> HashSet<(usize, usize)> {
+        rows.iter()
+            .enumerate()
+            .flat_map(|(y, row)| {
+                row.chars()
+                    .enumerate()
+                    .filter(|&(_, c)| c == '#')
+                    .map(move |(x, _)| (x, y))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ocr() {
+        let paper = dots(&[
+            "#..#.####.###..#....",
+            "#..#.#....#..#.#....",
+            "####.###..#..#.#....",
+            "#..#.#....###..#....",
+            "#..#.#....#.#..#....",
+            "#..#.####.#..#.####.",
+        ]);
+        assert_eq!(Ok(String::from("HERL")), ocr(&paper));
+    }
+
+    #[test]
+    fn test_ocr_unrecognized() {
+        let paper = dots(&[
+            "####.####",
+            "#..#.#...",
+            "####.#...",
+            "#..#.#...",
+            "#..#.#...",
+            "####.####",
+        ]);
+        let err = ocr(&paper).unwrap_err();
+        assert!(
+            err.starts_with("unrecognized glyph at letter 1\n####."),
+            "{}",
+            err
+        );
+
+        // The example folds into a square, not a letter
+        assert!(part_2(&generator(TEST_INPUT).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let frames = frames(&generator(TEST_INPUT).unwrap()).unwrap();
+        assert_eq!(3, frames.len());
+        let first = frames[0].lines().collect::<Vec<_>>();
+        assert_eq!("initial", first[0]);
+        assert_eq!(16, first.len());
+        assert_eq!("...#..#..#.", first[1]);
+        assert_eq!("-----------", first[8]);
+        assert_eq!(
+            "after fold along y=7
+#.##.|#..#.
+#...#|.....
+.....|#...#
+#...#|.....
+.#.#.|#.###
+.....|.....
+.....|.....
+",
+            frames[1]
+        );
+        assert_eq!(
+            "after fold along x=5
+#####
+#...#
+#...#
+#...#
+#####
+.....
+.....
+",
+            frames[2]
+        );
+    }
+
+    #[test]
+    fn test_generator_mixed() {
+        let input =
+            generator("  6 , 10 \n fold along  y = 7 \n0,14\n\n9,10\nfold along x=5\n").unwrap();
+        assert_eq!(
+            "Input { dots: [(6, 10), (0, 14), (9, 10)], folds: [Y(7), X(5)] }",
+            format!("{:?}", input)
+        );
+        assert_eq!(
+            Err(String::from("line 2: can't parse \"fold along z=3\"")),
+            generator("1,2\nfold along z=3").map(|_| ())
+        );
+        assert!(generator("1,2,3").is_err());
+        assert!(generator("fold along y=").is_err());
+    }
+
+    #[test]
+    fn test_asymmetric_fold() {
+        let input = generator("0,0\n1,3\n2,6\n3,5\nfold along y=2").unwrap();
+        let papers = snapshots(&input).unwrap();
+        let paper = &papers[1];
+        assert_eq!((4, 4), (paper.width, paper.height));
+        let dots: HashSet<_> = [(0, 2), (1, 3), (2, 0), (3, 1)].into_iter().collect();
+        assert_eq!(dots, paper.dots);
+
+        let input = generator("0,0\n6,1\n2,1\nfold along x=1").unwrap();
+        let paper = &snapshots(&input).unwrap()[1];
+        assert_eq!((5, 2), (paper.width, paper.height));
+        let dots: HashSet<_> = [(4, 0), (0, 1), (4, 1)].into_iter().collect();
+        assert_eq!(dots, paper.dots);
+    }
+
+    #[test]
+    fn test_dot_on_fold_line() {
+        let input = generator("0,0\n3,7\n4,14\nfold along y=7").unwrap();
+        assert_eq!(
+            Err(String::from(
+                "fold along y=7: dot 3,7 lies on the fold line"
+            )),
+            part_1(&input)
+        );
+    }
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) as usize % n
+        }
+    }
+
+    #[test]
+    fn test_bit_paper() {
+        let mut rng = Rng(13);
+        for _ in 0..200 {
+            let (w, h) = (1 + rng.below(300), 1 + rng.below(300));
+            let dots = (0..rng.below(60))
+                .map(|_| (rng.below(w), rng.below(h)))
+                .collect::<Vec<_>>();
+            let mut paper = Paper::new(&dots);
+            let mut bit_paper = BitPaper::new(&dots);
+            for _ in 0..4 {
+                let fold = if rng.below(2) == 0 {
+                    Fold::X(rng.below(paper.width + 1))
+                } else {
+                    Fold::Y(rng.below(paper.height + 1))
+                };
+                let rv = paper.fold(&fold);
+                assert_eq!(rv, bit_paper.fold(&fold));
+                if rv.is_err() {
+                    break;
+                }
+                assert_eq!(
+                    (paper.width, paper.height),
+                    (bit_paper.width, bit_paper.height)
+                );
+                assert_eq!(paper.dots, bit_paper.dots());
+                assert_eq!(paper.dots.len(), bit_paper.count());
+            }
+        }
+    }
+
+    #[test]
+    fn test_bit_paper_large() {
+        let mut rng = Rng(100_000);
+        let n = 20_000;
+        let dots = (0..200_000)
+            .map(|_| (rng.below(n), rng.below(n)))
+            .filter(|&(x, y)| x != n / 2 && y != n / 4 && y != 2 * n / 3)
+            .collect::<Vec<_>>();
+        let mut paper = Paper::new(&dots);
+        let mut bit_paper = BitPaper::new(&dots);
+        // Kept half larger, then folded half larger, then past the edge
+        for fold in [
+            Fold::X(n / 2),
+            Fold::Y(2 * n / 3),
+            Fold::Y(n / 4),
+            Fold::X(n),
+        ] {
+            paper.fold(&fold).unwrap();
+            bit_paper.fold(&fold).unwrap();
+            assert_eq!(
+                (paper.width, paper.height),
+                (bit_paper.width, bit_paper.height)
+            );
+            assert_eq!(paper.dots.len(), bit_paper.count());
+        }
+        assert_eq!(paper.dots, bit_paper.dots());
     }
 
     #[test]
     fn test_part_1() {
-        let input = generator(TEST_INPUT);
-        assert_eq!(17, part_1(&input));
+        let input = generator(TEST_INPUT).unwrap();
+        assert_eq!(Ok(17), part_1(&input));
     }
 }
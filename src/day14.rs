@@ -1,12 +1,17 @@
 aoc_main::main! {
     year 2021;
-    day14 : generator => part_1, part_2;
+    day14 : generator? => part_1, part_2;
 }
 
 mod day14 {
-    use std::{cmp, collections::HashMap};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fmt,
+    };
 
     use itertools::Itertools;
+    use num_bigint::{BigInt, BigUint};
+    use num_traits::{CheckedAdd, One, Zero};
 
     #[derive(Debug)]
     pub struct Input {
@@ -14,26 +19,108 @@ mod day14 {
         rules: HashMap<(u8, u8), u8>,
     }
 
-    pub fn generator(input: &str) -> Input {
-        let mut split = input.split("\n\n");
-        let template = split.next().unwrap().bytes().collect_vec();
-        let rules = split
-            .next()
-            .unwrap()
-            .lines()
-            .map(|l| {
-                let tmp = l.split(" -> ").collect_vec();
-                // dbg!(tmp);
-                (
-                    (
-                        (tmp[0].as_bytes()[0]),
-                        (tmp[0].as_bytes()[1]),
-                    ),
-                    (tmp[1].bytes().next().unwrap()),
-                )
-            })
-            .collect();
-        Input { template, rules }
+    type Rule = (usize, Pair, u8);
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum Issue {
+        UnknownElement { line: usize, element: char },
+        Duplicate { line: usize, first: usize },
+        Conflict { line: usize, first: usize },
+    }
+
+    impl fmt::Display for Issue {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Issue::UnknownElement { line, element } => {
+                    write!(
+                        f,
+                        "line {}: element {} is not in the template",
+                        line, element
+                    )
+                }
+                Issue::Duplicate { line, first } => {
+                    write!(f, "line {}: duplicate of the rule on line {}", line, first)
+                }
+                Issue::Conflict { line, first } => {
+                    write!(
+                        f,
+                        "line {}: conflicts with the rule on line {}",
+                        line, first
+                    )
+                }
+            }
+        }
+    }
+
+    fn element(c: char, line: usize) -> Result<u8, String> {
+        if c.is_ascii_graphic() {
+            Ok(c as u8)
+        } else {
+            Err(format!("line {}: invalid element {:?}", line, c))
+        }
+    }
+
+    fn parse(input: &str) -> Result<(Vec<u8>, Vec<Rule>), String> {
+        let mut lines = input.lines().enumerate().map(|(i, l)| (i + 1, l.trim()));
+        let template = match lines.next() {
+            Some((_, l)) if !l.is_empty() => l.chars().map(|c| element(c, 1)).try_collect()?,
+            _ => return Err("line 1: missing template".to_string()),
+        };
+        let mut rules = Vec::new();
+        for (line, l) in lines.filter(|(_, l)| !l.is_empty()) {
+            let malformed = || format!("line {}: expected `AB -> C`, found `{}`", line, l);
+            let (lhs, rhs) = l.split_once("->").ok_or_else(malformed)?;
+            let lhs: Vec<char> = lhs.trim().chars().collect();
+            let rhs: Vec<char> = rhs.trim().chars().collect();
+            if lhs.len() != 2 || rhs.len() != 1 {
+                return Err(malformed());
+            }
+            let pair = (element(lhs[0], line)?, element(lhs[1], line)?);
+            rules.push((line, pair, element(rhs[0], line)?));
+        }
+        Ok((template, rules))
+    }
+
+    fn issues(template: &[u8], rules: &[Rule]) -> Vec<Issue> {
+        let mut rv = Vec::new();
+        let mut seen: HashMap<Pair, (usize, u8)> = HashMap::new();
+        for &(line, (a, b), c) in rules {
+            for e in [a, b, c].into_iter().unique() {
+                if !template.contains(&e) {
+                    let element = e as char;
+                    rv.push(Issue::UnknownElement { line, element });
+                }
+            }
+            match seen.get(&(a, b)) {
+                Some(&(first, prev)) if prev == c => rv.push(Issue::Duplicate { line, first }),
+                Some(&(first, _)) => rv.push(Issue::Conflict { line, first }),
+                None => {
+                    seen.insert((a, b), (line, c));
+                }
+            }
+        }
+        rv
+    }
+
+    // Elements introduced only by insertions are reported too, so this is
+    // advisory; generator only rejects conflicts
+    pub fn validate(input: &str) -> Result<Vec<Issue>, String> {
+        let (template, rules) = parse(input)?;
+        Ok(issues(&template, &rules))
+    }
+
+    // Pairs without a rule are left alone. Other issues are printed as
+    // warnings
+    pub fn generator(input: &str) -> Result<Input, String> {
+        for issue in validate(input)? {
+            match issue {
+                Issue::Conflict { .. } => return Err(issue.to_string()),
+                _ => eprintln!("warning: {}", issue),
+            }
+        }
+        let (template, rules) = parse(input)?;
+        let rules = rules.into_iter().map(|(_, pair, c)| (pair, c)).collect();
+        Ok(Input { template, rules })
     }
 
     #[allow(dead_code)]
@@ -41,7 +128,9 @@ mod day14 {
         let mut new_chain = Vec::with_capacity(chain.len() * 2);
         for (&i, &j) in chain.iter().tuple_windows() {
             new_chain.push(i);
-            new_chain.push(*rules.get(&(i, j)).unwrap());
+            if let Some(&c) = rules.get(&(i, j)) {
+                new_chain.push(c);
+            }
         }
         new_chain.push(*chain.last().unwrap());
         new_chain
@@ -77,87 +166,387 @@ mod day14 {
         max_val.1 - min_val.1
     }
 
-    fn merge_map(
-        mut left_map: HashMap<u8, usize>,
-        mut right_map: HashMap<u8, usize>,
-    ) -> HashMap<u8, usize> {
-        for (k, v) in left_map.drain() {
-            let entry = right_map.entry(k).or_insert(0);
-            *entry += v;
+    type Pair = (u8, u8);
+
+    pub trait Count: Clone + Ord + Zero + CheckedAdd + From<u64> + TryFrom<BigUint> {}
+
+    impl<T: Clone + Ord + Zero + CheckedAdd + From<u64> + TryFrom<BigUint>> Count for T {}
+
+    type Poly = Vec<BigInt>;
+
+    // Pair counts evolve linearly: after a step, pair i's count is added to
+    // both pairs its rule produces
+    struct PairModel {
+        pairs: Vec<Pair>,
+        children: Vec<Vec<usize>>,
+        initial: Vec<u64>,
+        last: u8,
+    }
+
+    impl PairModel {
+        fn new(input: &Input) -> Self {
+            let mut index: HashMap<Pair, usize> = HashMap::new();
+            let mut pairs = Vec::new();
+            for (a, b) in input
+                .rules
+                .keys()
+                .copied()
+                .chain(input.template.iter().copied().tuple_windows())
+            {
+                let children = match input.rules.get(&(a, b)) {
+                    Some(&c) => vec![(a, c), (c, b)],
+                    None => vec![],
+                };
+                for pair in std::iter::once((a, b)).chain(children) {
+                    index.entry(pair).or_insert_with(|| {
+                        pairs.push(pair);
+                        pairs.len() - 1
+                    });
+                }
+            }
+            let children = pairs
+                .iter()
+                .map(|&(a, b)| match input.rules.get(&(a, b)) {
+                    Some(&c) => vec![index[&(a, c)], index[&(c, b)]],
+                    None => vec![index[&(a, b)]],
+                })
+                .collect();
+            let mut initial = vec![0; pairs.len()];
+            for (&a, &b) in input.template.iter().tuple_windows() {
+                initial[index[&(a, b)]] += 1;
+            }
+            Self {
+                pairs,
+                children,
+                initial,
+                last: *input.template.last().unwrap(),
+            }
+        }
+
+        fn step<T: Count>(&self, counts: &[T]) -> Option<Vec<T>> {
+            let mut rv = vec![T::zero(); counts.len()];
+            for (c, children) in counts.iter().zip(&self.children) {
+                if !c.is_zero() {
+                    for &j in children {
+                        rv[j] = rv[j].checked_add(c)?;
+                    }
+                }
+            }
+            Some(rv)
+        }
+
+        // det(xI - A) for the transition matrix A, lowest coefficient first,
+        // by Faddeev-LeVerrier. A has at most two entries per column, so each
+        // product with A is pairs^2 additions
+        fn char_poly(&self) -> Poly {
+            let n = self.pairs.len();
+            let mut coeffs = vec![BigInt::zero(); n + 1];
+            coeffs[n] = BigInt::one();
+            let mut m = (0..n)
+                .map(|i| (0..n).map(|j| BigInt::from((i == j) as u8)).collect_vec())
+                .collect_vec();
+            for k in 1..=n {
+                let mut am = vec![vec![BigInt::zero(); n]; n];
+                for (i, children) in self.children.iter().enumerate() {
+                    for &j in children {
+                        for (d, s) in am[j].iter_mut().zip(&m[i]) {
+                            if !s.is_zero() {
+                                *d += s;
+                            }
+                        }
+                    }
+                }
+                let c = -(0..n).map(|i| &am[i][i]).sum::<BigInt>() / k;
+                for (i, row) in am.iter_mut().enumerate() {
+                    row[i] += &c;
+                }
+                coeffs[n - k] = c;
+                m = am;
+            }
+            coeffs
+        }
+
+        // By Cayley-Hamilton A^steps is a polynomial in A of degree below
+        // pairs: x^steps mod det(xI - A). Each doubling costs pairs^2
+        // multiplies. A factor x^m of the characteristic polynomial is taken
+        // out by stepping m times first, as A^m kills that part of the space
+        fn pair_counts<T: Count>(&self, steps: usize) -> Option<Vec<T>> {
+            if steps <= self.pairs.len() {
+                let mut counts = self.initial.iter().map(|&c| T::from(c)).collect_vec();
+                for _ in 0..steps {
+                    counts = self.step(&counts)?;
+                }
+                return Some(counts);
+            }
+            let poly = self.char_poly();
+            let m = poly.iter().take_while(|c| c.is_zero()).count();
+            let mut counts = self.initial.iter().map(|&c| BigInt::from(c)).collect_vec();
+            for _ in 0..m {
+                counts = self.step(&counts)?;
+            }
+            let mut rv = vec![BigInt::zero(); counts.len()];
+            for coeff in x_pow_mod(steps - m, &poly[m..]) {
+                if !coeff.is_zero() {
+                    for (r, c) in rv.iter_mut().zip(&counts) {
+                        *r += &coeff * c;
+                    }
+                }
+                counts = self.step(&counts)?;
+            }
+            rv.into_iter()
+                .map(|c| T::try_from(c.to_biguint()?).ok())
+                .collect()
+        }
+
+        // Every element but the last starts exactly one pair
+        fn element_counts<T: Count>(&self, steps: usize) -> Option<BTreeMap<char, T>> {
+            let mut rv: BTreeMap<char, T> = BTreeMap::new();
+            rv.insert(self.last as char, T::from(1));
+            let counts = self.pairs.iter().zip(self.pair_counts::<T>(steps)?);
+            for (&(a, _), c) in counts.filter(|(_, c)| !c.is_zero()) {
+                let entry = rv.entry(a as char).or_insert_with(T::zero);
+                *entry = entry.checked_add(&c)?;
+            }
+            Some(rv)
         }
-        right_map
     }
 
-    #[allow(dead_code)]
-    fn print_map(map: &HashMap<u8, usize>) {
-        print!("{{");
-        for (k, v) in map {
-            print!("  {}:{} ", *k as char, v);
-        }
-        print!("}}");
-    }
-
-    fn recurse(
-        pair: (u8, u8),
-        rules: &HashMap<(u8, u8), u8>,
-        current_step: usize,
-        target_step: usize,
-        cache: &mut HashMap<(u8, u8, usize), HashMap<u8, usize>>,
-    ) -> HashMap<u8, usize> {
-        if let Some(v) = cache.get(&(pair.0, pair.1, current_step)) {
-            return v.clone();
-        }
-        if current_step == target_step {
-            let mut rv = HashMap::new();
-            rv.insert(pair.0, 1);
-            let entry = rv.entry(pair.1).or_insert(0);
-            *entry += 1;
-            return rv;
+    // x^n mod the monic p, by squaring from the top bit of n
+    fn x_pow_mod(n: usize, p: &[BigInt]) -> Poly {
+        let deg = p.len() - 1;
+        if deg == 0 {
+            return vec![];
+        }
+        let mut rv = vec![BigInt::zero(); deg];
+        rv[0] = BigInt::one();
+        for bit in (0..usize::BITS - n.leading_zeros()).rev() {
+            rv = reduce(square(&rv), p);
+            if n >> bit & 1 == 1 {
+                rv.insert(0, BigInt::zero());
+                rv = reduce(rv, p);
+            }
         }
-        let &val = rules.get(&pair).unwrap();
-        let left_map = recurse((pair.0, val), rules, current_step + 1, target_step, cache);
-        let right_map = recurse((val, pair.1), rules, current_step + 1, target_step, cache);
-        let rv = merge_map(left_map, right_map);
-        cache.insert((pair.0, pair.1, current_step), rv.clone());
         rv
     }
 
-    fn using_dfs(input: &Input, target_step: usize) -> usize {
-        let mut rv_map = HashMap::new();
-        let mut cache = HashMap::new();
-        for (&i, &j) in input.template.iter().tuple_windows() {
-            rv_map = merge_map(
-                rv_map,
-                recurse((i, j), &input.rules, 0, target_step, &mut cache),
-            );
+    // Cross terms once, doubled, then the squares
+    fn square(a: &[BigInt]) -> Poly {
+        let mut rv = vec![BigInt::zero(); 2 * a.len() - 1];
+        for (i, x) in a.iter().enumerate().filter(|(_, x)| !x.is_zero()) {
+            for (j, y) in a
+                .iter()
+                .enumerate()
+                .skip(i + 1)
+                .filter(|(_, y)| !y.is_zero())
+            {
+                rv[i + j] += x * y;
+            }
         }
-        let mut min_val = usize::MAX;
-        let mut max_val = 0;
-        for (k, v) in rv_map {
-            let mut tmp = v / 2;
-            if k == input.template[0] {
-                tmp += 1;
+        for r in rv.iter_mut() {
+            *r <<= 1;
+        }
+        for (i, x) in a.iter().enumerate() {
+            rv[2 * i] += x * x;
+        }
+        rv
+    }
+
+    // Replaces x^deg by -(p_0 + ... + p_{deg-1} x^{deg-1}) from the top down
+    fn reduce(mut a: Poly, p: &[BigInt]) -> Poly {
+        let deg = p.len() - 1;
+        for d in (deg..a.len()).rev() {
+            let top = std::mem::take(&mut a[d]);
+            if !top.is_zero() {
+                for (i, c) in p[..deg].iter().enumerate().filter(|(_, c)| !c.is_zero()) {
+                    a[d - deg + i] -= &top * c;
+                }
             }
-            if k == input.template[input.template.len() - 1] {
-                tmp += 1;
+        }
+        a.truncate(deg);
+        a
+    }
+
+    // All queries return None if a count overflows T
+    pub fn frequencies<T: Count>(input: &Input, steps: usize) -> Option<BTreeMap<char, T>> {
+        PairModel::new(input).element_counts(steps)
+    }
+
+    pub fn most_common<T: Count>(input: &Input, steps: usize) -> Option<(char, T)> {
+        frequencies::<T>(input, steps)?
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1))
+    }
+
+    pub fn least_common<T: Count>(input: &Input, steps: usize) -> Option<(char, T)> {
+        frequencies::<T>(input, steps)?
+            .into_iter()
+            .min_by(|a, b| a.1.cmp(&b.1))
+    }
+
+    #[allow(dead_code)]
+    pub fn polymer_length<T: Count>(input: &Input, steps: usize) -> Option<T> {
+        PairModel::new(input)
+            .pair_counts::<T>(steps)?
+            .iter()
+            .try_fold(T::from(1), |acc, c| acc.checked_add(c))
+    }
+
+    #[allow(dead_code)]
+    pub fn pair_count<T: Count>(input: &Input, steps: usize, pair: (char, char)) -> Option<T> {
+        let model = PairModel::new(input);
+        let counts = model.pair_counts::<T>(steps)?;
+        Some(
+            model
+                .pairs
+                .iter()
+                .position(|&(a, b)| (a as char, b as char) == pair)
+                .map_or_else(T::zero, |i| counts[i].clone()),
+        )
+    }
+
+    // Length of the expansion of each pair after n steps, not counting its
+    // second element, saturating at u64::MAX. Pairs without a rule stay at 1.
+    // Levels stop once they repeat: past that point every pair either has a
+    // finite expansion or is saturated, so later levels are all identical
+    struct PolymerIndex<'a> {
+        input: &'a Input,
+        steps: usize,
+        lens: Vec<HashMap<Pair, u64>>,
+    }
+
+    impl<'a> PolymerIndex<'a> {
+        fn new(input: &'a Input, steps: usize) -> Self {
+            let mut lens = vec![input.rules.keys().map(|&pair| (pair, 1)).collect()];
+            for n in 1..=steps {
+                let prev: &HashMap<Pair, u64> = &lens[n - 1];
+                let len = |pair| prev.get(&pair).copied().unwrap_or(1);
+                let next: HashMap<Pair, u64> = input
+                    .rules
+                    .iter()
+                    .map(|(&(a, b), &c)| ((a, b), len((a, c)).saturating_add(len((c, b)))))
+                    .collect();
+                if &next == prev {
+                    break;
+                }
+                lens.push(next);
+            }
+            Self { input, steps, lens }
+        }
+
+        fn top(&self) -> usize {
+            self.lens.len() - 1
+        }
+
+        fn pair_len(&self, n: usize, pair: Pair) -> u64 {
+            self.lens[n.min(self.top())]
+                .get(&pair)
+                .copied()
+                .unwrap_or(1)
+        }
+
+        fn len(&self) -> u64 {
+            self.input
+                .template
+                .iter()
+                .tuple_windows()
+                .fold(1, |acc: u64, (&a, &b)| {
+                    acc.saturating_add(self.pair_len(self.steps, (a, b)))
+                })
+        }
+
+        fn slice(&self, i: u64, j: u64) -> String {
+            let mut out = String::new();
+            let mut offset = 0;
+            for (&a, &b) in self.input.template.iter().tuple_windows() {
+                self.emit((a, b), self.steps, offset, i, j, &mut out);
+                offset = offset.saturating_add(self.pair_len(self.steps, (a, b)));
+            }
+            if (i..j).contains(&offset) {
+                out.push(*self.input.template.last().unwrap() as char);
+            }
+            out
+        }
+
+        // Above the top level, a saturated pair whose left child is also
+        // saturated pushes its right child past u64::MAX, so the walk only
+        // follows left children. That chain cycles through at most one pair
+        // per rule, so whole cycles can be skipped
+        fn follow_saturated(&self, mut pair: Pair, mut n: usize) -> (Pair, usize) {
+            let top = self.top();
+            let mut seen = HashMap::new();
+            while n > top {
+                let left = match self.input.rules.get(&pair) {
+                    Some(&c) => (pair.0, c),
+                    None => break,
+                };
+                if self.pair_len(n, pair) != u64::MAX || self.pair_len(n - 1, left) != u64::MAX {
+                    break;
+                }
+                if let Some(prev) = seen.insert(pair, n) {
+                    let cycle = prev - n;
+                    n = top + 1 + (n - top - 1) % cycle;
+                    seen.clear();
+                    seen.insert(pair, n);
+                }
+                pair = left;
+                n -= 1;
+            }
+            (pair, n)
+        }
+
+        fn emit(&self, pair: Pair, n: usize, offset: u64, i: u64, j: u64, out: &mut String) {
+            let mut stack = vec![(pair, n, offset)];
+            while let Some((pair, n, offset)) = stack.pop() {
+                if offset >= j || offset.saturating_add(self.pair_len(n, pair)) <= i {
+                    continue;
+                }
+                let (pair, n) = self.follow_saturated(pair, n);
+                match self.input.rules.get(&pair) {
+                    Some(&c) if n > 0 => {
+                        let left = (pair.0, c);
+                        let right_offset = offset.saturating_add(self.pair_len(n - 1, left));
+                        stack.push(((c, pair.1), n - 1, right_offset));
+                        stack.push((left, n - 1, offset));
+                    }
+                    _ => out.push(pair.0 as char),
+                }
             }
-            min_val = cmp::min(min_val, tmp);
-            max_val = cmp::max(max_val, tmp);
         }
-        max_val - min_val
+    }
+
+    #[allow(dead_code)]
+    pub fn polymer_slice(input: &Input, steps: usize, i: u64, j: u64) -> String {
+        PolymerIndex::new(input, steps).slice(i, j)
+    }
+
+    #[allow(dead_code)]
+    pub fn char_at(input: &Input, steps: usize, i: u64) -> Option<char> {
+        let index = PolymerIndex::new(input, steps);
+        if i >= index.len() {
+            return None;
+        }
+        index.slice(i, i + 1).chars().next()
+    }
+
+    fn max_minus_min(input: &Input, steps: usize) -> usize {
+        let (_, max_val) = most_common::<u128>(input, steps).expect("counts overflow u128");
+        let (_, min_val) = least_common::<u128>(input, steps).unwrap();
+        (max_val - min_val).try_into().unwrap()
     }
 
     pub fn part_1(input: &Input) -> usize {
-        using_dfs(input, 10)
+        max_minus_min(input, 10)
     }
 
     pub fn part_2(input: &Input) -> usize {
-        using_dfs(input, 40)
+        max_minus_min(input, 40)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use num_bigint::BigUint;
+
     use super::day14::*;
 
     const TEST_INPUT: &str = "NNCB
@@ -182,12 +571,212 @@ CN -> C";
     #[test]
     #[ignore]
     fn test_generator() {
-        println!("{:?}", generator(TEST_INPUT));
+        println!("{:?}", generator(TEST_INPUT).unwrap());
     }
 
     #[test]
     fn test_part_1() {
-        let input = generator(TEST_INPUT);
+        let input = generator(TEST_INPUT).unwrap();
         assert_eq!(1588, part_1(&input));
     }
+
+    #[test]
+    fn test_part_2() {
+        let input = generator(TEST_INPUT).unwrap();
+        assert_eq!(2188189693529, part_2(&input));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let input = generator(TEST_INPUT).unwrap();
+        assert!(frequencies::<u128>(&input, 100).is_some());
+        assert!(frequencies::<u128>(&input, 200).is_none());
+    }
+
+    #[test]
+    fn test_big_counts() {
+        let input = generator(TEST_INPUT).unwrap();
+        let small = frequencies::<u128>(&input, 100).unwrap();
+        let big = frequencies::<BigUint>(&input, 100).unwrap();
+        for (c, n) in small {
+            assert_eq!(BigUint::from(n), big[&c]);
+        }
+        // The polymer doubles in gaps every step
+        let big = frequencies::<BigUint>(&input, 1000).unwrap();
+        let total: BigUint = big.values().sum();
+        assert_eq!(
+            BigUint::from(3u32) * BigUint::from(2u32).pow(1000) + 1u32,
+            total
+        );
+    }
+
+    #[test]
+    fn test_counts_match_polymer() {
+        // More steps than the example has pairs, so past direct stepping
+        let input = generator(TEST_INPUT).unwrap();
+        let polymer = polymer_slice(&input, 18, 0, u64::MAX);
+        let freqs = frequencies::<u64>(&input, 18).unwrap();
+        for (c, n) in freqs {
+            assert_eq!(polymer.chars().filter(|&p| p == c).count() as u64, n);
+        }
+    }
+
+    // About 1s in release on the example's 16 pairs
+    #[test]
+    #[ignore]
+    fn test_million_steps() {
+        let input = generator(TEST_INPUT).unwrap();
+        let big = frequencies::<BigUint>(&input, 1_000_000).unwrap();
+        let total: BigUint = big.values().sum();
+        assert_eq!(
+            BigUint::from(3u32) * BigUint::from(2u32).pow(1_000_000) + 1u32,
+            total
+        );
+    }
+
+    // About 40s in release on the 100 pairs of the puzzle input
+    #[test]
+    #[ignore]
+    fn test_million_steps_full_rules() {
+        let input = std::fs::read_to_string("input/2021/day14.txt").unwrap();
+        let input = generator(&input).unwrap();
+        let big = frequencies::<BigUint>(&input, 1_000_000).unwrap();
+        let total: BigUint = big.values().sum();
+        assert_eq!(
+            BigUint::from(19u32) * BigUint::from(2u32).pow(1_000_000) + 1u32,
+            total
+        );
+    }
+
+    #[test]
+    fn test_queries() {
+        let input = generator(TEST_INPUT).unwrap();
+        let freqs = frequencies::<u64>(&input, 10).unwrap();
+        assert_eq!(
+            vec![('B', 1749), ('C', 298), ('H', 161), ('N', 865)],
+            freqs.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(('B', 1749)), most_common::<u64>(&input, 10));
+        assert_eq!(Some(('H', 161)), least_common::<u64>(&input, 10));
+        assert_eq!(Some(3073), polymer_length::<u64>(&input, 10));
+        // NCNBCHB
+        assert_eq!(Some(1), pair_count::<u64>(&input, 1, ('C', 'H')));
+        assert_eq!(Some(0), pair_count::<u64>(&input, 1, ('B', 'B')));
+        assert_eq!(Some(0), pair_count::<u64>(&input, 1, ('X', 'Y')));
+    }
+
+    #[test]
+    fn test_polymer_slice() {
+        let input = generator(TEST_INPUT).unwrap();
+        let step_4 = "NBBNBNBBCCNBCNCCNBBNBBNBBBNBBNBBCBHCBHHNHCBBCBHCB";
+        let n = step_4.len() as u64;
+        for i in 0..=n {
+            for j in i..=n + 2 {
+                let expected = &step_4[i as usize..(j.min(n)) as usize];
+                assert_eq!(expected, polymer_slice(&input, 4, i, j));
+            }
+        }
+        for (i, c) in step_4.chars().enumerate() {
+            assert_eq!(Some(c), char_at(&input, 4, i as u64));
+        }
+        assert_eq!(None, char_at(&input, 4, n));
+    }
+
+    #[test]
+    fn test_polymer_slice_large() {
+        let input = generator(TEST_INPUT).unwrap();
+        let i = 1_000_000_000_000;
+        let slice = polymer_slice(&input, 40, i, i + 50);
+        assert_eq!(50, slice.len());
+        let chars: String = (i..i + 50)
+            .map(|k| char_at(&input, 40, k).unwrap())
+            .collect();
+        assert_eq!(chars, slice);
+        assert_eq!(Some('B'), char_at(&input, 40, 3 * (1 << 40)));
+        assert_eq!(None, char_at(&input, 40, 3 * (1 << 40) + 1));
+        // Lengths saturate long before 1000 steps
+        assert_eq!(3, polymer_slice(&input, 1000, i, i + 3).len());
+    }
+
+    #[test]
+    fn test_polymer_slice_deep() {
+        let input = generator(TEST_INPUT).unwrap();
+        assert_eq!(5, polymer_slice(&input, 20_000, 5, 10).len());
+        // A prefix after n steps is the prefix after n - 1 steps with one
+        // round of insertions applied
+        let prev = polymer_slice(&input, 99_999, 0, 50);
+        let rules = TEST_INPUT.split("\n\n").nth(1).unwrap();
+        let expanded = generator(&format!("{}\n\n{}", prev, rules)).unwrap();
+        assert_eq!(
+            polymer_slice(&expanded, 1, 0, 50),
+            polymer_slice(&input, 100_000, 0, 50)
+        );
+        let i = u64::MAX / 2;
+        assert_eq!(20, polymer_slice(&input, 100_000, i, i + 20).len());
+
+        // AB -> A grows by one each step and never saturates
+        let input = generator("AB\n\nAB -> A\n").unwrap();
+        assert_eq!(Some('A'), char_at(&input, 100_000, 100_000));
+        assert_eq!(Some('B'), char_at(&input, 100_000, 100_001));
+        assert_eq!(None, char_at(&input, 100_000, 100_002));
+    }
+
+    #[test]
+    fn test_partial_rules() {
+        let input = generator("NNCB\n\nNN -> C\nNC -> B\n").unwrap();
+        // NCNBCB, then NBCNBCB
+        assert_eq!("NBCNBCB", polymer_slice(&input, 2, 0, 100));
+        assert_eq!(Some(7), polymer_length::<u64>(&input, 2));
+        let freqs = frequencies::<u64>(&input, 2).unwrap();
+        assert_eq!(Some(&3), freqs.get(&'B'));
+        assert_eq!(Some(&2), freqs.get(&'C'));
+        assert_eq!(Some(&2), freqs.get(&'N'));
+        assert_eq!(0, part_1(&generator("AB\n\nCD -> E").unwrap()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let input = "NNCB
+
+NN -> C
+NC -> B
+NN -> C
+CB -> X
+NC -> N";
+        assert_eq!(
+            Ok(vec![
+                Issue::Duplicate { line: 5, first: 3 },
+                Issue::UnknownElement {
+                    line: 6,
+                    element: 'X'
+                },
+                Issue::Conflict { line: 7, first: 4 },
+            ]),
+            validate(input)
+        );
+        assert_eq!(
+            Err("line 7: conflicts with the rule on line 4".to_string()),
+            generator(input).map(|_| ())
+        );
+        assert!(validate(TEST_INPUT)
+            .unwrap()
+            .iter()
+            .all(|i| matches!(i, Issue::UnknownElement { element: 'H', .. })));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(
+            Err("line 3: expected `AB -> C`, found `ABC -> D`".to_string()),
+            generator("AB\n\nABC -> D").map(|_| ())
+        );
+        assert_eq!(
+            Err("line 4: expected `AB -> C`, found `AB => C`".to_string()),
+            generator("AB\n\nBA -> C\nAB => C").map(|_| ())
+        );
+        assert_eq!(
+            Err("line 1: missing template".to_string()),
+            generator("\nAB -> C").map(|_| ())
+        );
+    }
 }
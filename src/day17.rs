@@ -1,60 +1,559 @@
 aoc_main::main! {
     year 2021;
-    day17 : generator => part_1, part_2;
+    day17 : generator? => part_1?, part_2?;
 }
 
 mod day17 {
-    use std::cmp;
+    use std::{
+        cmp,
+        collections::{BTreeMap, HashSet},
+        env, fmt,
+        fs::File,
+        io::{self, Write},
+        ops::RangeInclusive,
+    };
 
     use itertools::Itertools;
 
+    // ((x_min, x_max), (y_max, y_min))
     type Target = ((isize, isize), (isize, isize));
-    pub fn generator(_input: &str) -> Target {
-        ((137, 171), (-73, -98))
+
+    peg::parser! {
+      grammar target_parser() for str {
+        rule number() -> isize
+          = n:$("-"? ['0'..='9']+) {? n.parse().or(Err("isize")) }
+
+        rule range() -> (isize, isize)
+          = a:number() ".." b:number() { (cmp::min(a, b), cmp::max(a, b)) }
+
+        pub rule target() -> Target
+          = "target area: x=" x:range() ", y=" y:range() { (x, (y.1, y.0)) }
+      }
+    }
+
+    pub fn generator(input: &str) -> Result<Target, String> {
+        target_parser::target(input.trim()).map_err(|e| e.to_string())
     }
 
-    fn simulate(mut x_vel: isize, mut y_vel: isize, target: Target) -> Option<isize> {
-        let mut x = 0;
-        let mut y = 0;
-        let mut max_y = 0;
-        loop {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Outcome {
+        Hit,
+        OvershotX,
+        StalledShort,
+        FellBelow,
+    }
+
+    impl fmt::Display for Outcome {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let s = match self {
+                Outcome::Hit => "hit",
+                Outcome::OvershotX => "overshot x",
+                Outcome::StalledShort => "stalled short",
+                Outcome::FellBelow => "fell below",
+            };
+            write!(f, "{}", s)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Physics {
+        pub drag: isize,
+        pub gravity: isize,
+        pub start: (isize, isize),
+    }
+
+    impl Default for Physics {
+        fn default() -> Self {
+            Physics {
+                drag: 1,
+                gravity: 1,
+                start: (0, 0),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Mode {
+        InOrder,
+        Any,
+    }
+
+    // positions[0] is the launcher, so the outcome is decided on step
+    // positions.len() - 1. hits holds (target index, step) pairs
+    #[derive(Debug)]
+    pub struct Trajectory {
+        pub targets: Vec<Target>,
+        pub positions: Vec<(isize, isize)>,
+        #[allow(dead_code)]
+        pub hits: Vec<(usize, usize)>,
+        pub outcome: Outcome,
+    }
+
+    fn contains(target: &Target, (x, y): (isize, isize)) -> bool {
+        let ((x_min, x_max), (y_max, y_min)) = *target;
+        x >= x_min && x <= x_max && y <= y_max && y >= y_min
+    }
+
+    // Whether a probe at (x, y), about to move by vel, can no longer reach the
+    // target. dir is the launch direction along x
+    fn rejection(
+        target: &Target,
+        dir: isize,
+        (x, y): (isize, isize),
+        vel: (isize, isize),
+    ) -> Option<Outcome> {
+        let ((x_min, x_max), (_, y_min)) = *target;
+        if (dir > 0 && x > x_max) || (dir < 0 && x < x_min) {
+            Some(Outcome::OvershotX)
+        } else if vel.0 == 0 && (x < x_min || x > x_max) {
+            Some(Outcome::StalledShort)
+        } else if y < y_min && vel.1 <= 0 {
+            Some(Outcome::FellBelow)
+        } else {
+            None
+        }
+    }
+
+    fn check(physics: &Physics, targets: &[Target]) -> Result<(), String> {
+        if physics.drag < 0 || physics.gravity < 1 {
+            return Err("drag must be at least 0 and gravity at least 1".to_string());
+        }
+        if targets.is_empty() {
+            return Err("no targets".to_string());
+        }
+        Ok(())
+    }
+
+    // With InOrder the probe must pass through every target in turn, with Any
+    // it stops at the first target it enters. A miss reports why the last
+    // target still in play was lost
+    pub fn fly(
+        physics: &Physics,
+        mut x_vel: isize,
+        mut y_vel: isize,
+        targets: &[Target],
+        mode: Mode,
+    ) -> Result<Trajectory, String> {
+        check(physics, targets)?;
+        let dir = x_vel.signum();
+        let (mut x, mut y) = physics.start;
+        let mut positions = vec![(x, y)];
+        let mut hits = Vec::new();
+        let mut rejected: Vec<Option<Outcome>> = vec![None; targets.len()];
+        let outcome = loop {
             x += x_vel;
             y += y_vel;
-            max_y = cmp::max(max_y, y);
-            if x >= target.0 .0 && x <= target.0 .1 && y <= target.1 .0 && y >= target.1 .1 {
-                return Some(max_y);
+            positions.push((x, y));
+            let step = positions.len() - 1;
+            match mode {
+                Mode::InOrder => {
+                    while hits.len() < targets.len() && contains(&targets[hits.len()], (x, y)) {
+                        hits.push((hits.len(), step));
+                    }
+                    if hits.len() == targets.len() {
+                        break Outcome::Hit;
+                    }
+                }
+                Mode::Any => {
+                    if let Some(i) = targets.iter().position(|t| contains(t, (x, y))) {
+                        hits.push((i, step));
+                        break Outcome::Hit;
+                    }
+                }
             }
-            if x_vel == 0 && x < target.0 .0 {
-                return None;
+            x_vel -= x_vel.signum() * cmp::min(physics.drag, x_vel.abs());
+            y_vel -= physics.gravity;
+            let vel = (x_vel, y_vel);
+            match mode {
+                Mode::InOrder => {
+                    if let Some(r) = rejection(&targets[hits.len()], dir, (x, y), vel) {
+                        break r;
+                    }
+                }
+                Mode::Any => {
+                    let mut last = None;
+                    for (t, r) in targets.iter().zip(rejected.iter_mut()) {
+                        if r.is_none() {
+                            *r = rejection(t, dir, (x, y), vel);
+                            last = last.or(*r);
+                        }
+                    }
+                    if rejected.iter().all(Option::is_some) {
+                        break last.unwrap();
+                    }
+                }
             }
-            if x > target.0 .1 {
-                return None;
+        };
+        Ok(Trajectory {
+            targets: targets.to_vec(),
+            positions,
+            hits,
+            outcome,
+        })
+    }
+
+    pub fn trajectory(x_vel: isize, y_vel: isize, target: Target) -> Trajectory {
+        fly(&Physics::default(), x_vel, y_vel, &[target], Mode::InOrder).unwrap()
+    }
+
+    // Where a probe launched with x velocity v comes to rest, relative to the
+    // launcher, or None if it never does
+    fn stall(physics: &Physics, v: isize) -> Option<isize> {
+        if v == 0 {
+            return Some(0);
+        }
+        if physics.drag == 0 {
+            return None;
+        }
+        let moving = ceil_div(v.abs(), physics.drag);
+        Some(v.signum() * (moving * v.abs() - physics.drag * tri(moving - 1)))
+    }
+
+    // Every target is entered no later than its first step, so a faster launch
+    // overshoots along x. Upwards, a hit at relative height r on step k needs
+    // k * y_vel - gravity * k * (k - 1) / 2 = r: either r = 0 or k <= 2|r|,
+    // which bounds y_vel by |r| * (gravity + 1). A target spanning the launch
+    // height is hit by ever faster launches once the probe stalls in its
+    // columns. Otherwise the probe moves every step it can still hit, so
+    // k <= x_far and y_vel is at most y_far + gravity * x_far
+    fn search(
+        physics: &Physics,
+        targets: &[Target],
+        mode: Mode,
+    ) -> Result<Vec<(isize, isize)>, String> {
+        check(physics, targets)?;
+        let (sx, sy) = physics.start;
+        let x_far = targets
+            .iter()
+            .map(|&((x_min, x_max), _)| cmp::max((x_min - sx).abs(), (x_max - sx).abs()))
+            .max()
+            .unwrap();
+        let y_far = targets
+            .iter()
+            .map(|&(_, (y_max, y_min))| cmp::max((y_min - sy).abs(), (y_max - sy).abs()))
+            .max()
+            .unwrap();
+        let spanning = targets
+            .iter()
+            .filter(|&&(_, (y_max, y_min))| y_min <= sy && sy <= y_max)
+            .collect_vec();
+        let stalls_in = |&&((x_min, x_max), _): &&Target| {
+            (-x_far..=x_far)
+                .filter_map(|v| stall(physics, v))
+                .any(|x| x_min <= sx + x && sx + x <= x_max)
+        };
+        if spanning.iter().any(stalls_in) {
+            return Err(
+                "infinitely many velocities hit a target spanning the launch height".to_string(),
+            );
+        }
+        let y_lo = cmp::min(targets.iter().map(|t| t.1 .1 - sy).min().unwrap(), 0);
+        let mut y_hi = y_far * (physics.gravity + 1);
+        if !spanning.is_empty() {
+            y_hi = cmp::max(y_hi, y_far + physics.gravity * x_far);
+        }
+        let mut rv = Vec::new();
+        for (xv, yv) in (-x_far..=x_far).cartesian_product(y_lo..=y_hi) {
+            if fly(physics, xv, yv, targets, mode)?.outcome == Outcome::Hit {
+                rv.push((xv, yv));
             }
-            if y < target.1 .1 {
-                return None;
+        }
+        Ok(rv)
+    }
+
+    #[allow(dead_code)]
+    pub fn hits_all(physics: &Physics, targets: &[Target]) -> Result<Vec<(isize, isize)>, String> {
+        search(physics, targets, Mode::InOrder)
+    }
+
+    #[allow(dead_code)]
+    pub fn hits_any(physics: &Physics, targets: &[Target]) -> Result<Vec<(isize, isize)>, String> {
+        search(physics, targets, Mode::Any)
+    }
+
+    impl Trajectory {
+        pub fn step(&self) -> usize {
+            self.positions.len() - 1
+        }
+
+        pub fn max_height(&self) -> isize {
+            self.positions.iter().map(|p| p.1).max().unwrap()
+        }
+
+        fn bounds(&self) -> ((isize, isize), (isize, isize)) {
+            let corners = self
+                .targets
+                .iter()
+                .flat_map(|&((x_min, x_max), (y_max, y_min))| [(x_min, y_min), (x_max, y_max)]);
+            let (x, y) = self.positions[0];
+            self.positions
+                .iter()
+                .copied()
+                .chain(corners)
+                .fold(((x, x), (y, y)), |((x0, x1), (y0, y1)), (x, y)| {
+                    ((x0.min(x), x1.max(x)), (y0.min(y), y1.max(y)))
+                })
+        }
+
+        // Same layout as the puzzle's diagrams, y growing upwards
+        pub fn plot(&self) -> String {
+            let ((x0, x1), (y0, y1)) = self.bounds();
+            let probe: HashSet<_> = self.positions[1..].iter().collect();
+            (y0..=y1)
+                .rev()
+                .map(|y| {
+                    (x0..=x1)
+                        .map(|x| {
+                            if (x, y) == self.positions[0] {
+                                'S'
+                            } else if probe.contains(&(x, y)) {
+                                '#'
+                            } else if self.targets.iter().any(|t| contains(t, (x, y))) {
+                                'T'
+                            } else {
+                                '.'
+                            }
+                        })
+                        .collect::<String>()
+                })
+                .join("\n")
+        }
+
+        pub fn write_svg<W: Write>(&self, w: &mut W) -> io::Result<()> {
+            let ((x0, x1), (y0, y1)) = self.bounds();
+            let r = cmp::max(x1 - x0, y1 - y0) as f64 / 200.0 + 0.25;
+            // SVG y grows downwards, so every y is negated
+            writeln!(
+                w,
+                r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">"#,
+                x0 - 1,
+                -y1 - 1,
+                x1 - x0 + 2,
+                y1 - y0 + 2
+            )?;
+            for &((x_min, x_max), (y_max, y_min)) in &self.targets {
+                writeln!(
+                    w,
+                    r#"<rect x="{}" y="{}" width="{}" height="{}" fill="lightblue"/>"#,
+                    x_min as f64 - 0.5,
+                    -y_max as f64 - 0.5,
+                    x_max - x_min + 1,
+                    y_max - y_min + 1
+                )?;
             }
-            if x_vel != 0 {
-                x_vel -= 1;
+            let points = self
+                .positions
+                .iter()
+                .map(|(x, y)| format!("{},{}", x, -y))
+                .join(" ");
+            writeln!(
+                w,
+                r#"<polyline points="{}" fill="none" stroke="gray" stroke-width="{}"/>"#,
+                points,
+                r / 2.0
+            )?;
+            for (i, (x, y)) in self.positions.iter().enumerate() {
+                let colour = if i == 0 { "green" } else { "black" };
+                writeln!(
+                    w,
+                    r#"<circle cx="{}" cy="{}" r="{}" fill="{}"/>"#,
+                    x, -y, r, colour
+                )?;
             }
-            y_vel -= 1;
+            writeln!(w, "</svg>")
+        }
+    }
+
+    fn simulate(x_vel: isize, y_vel: isize, target: Target) -> Option<isize> {
+        let t = trajectory(x_vel, y_vel, target);
+        (t.outcome == Outcome::Hit).then(|| t.max_height())
+    }
+
+    // DAY17_SHOT="x,y" prints that shot, and DAY17_SVG also writes it as SVG
+    fn maybe_show_shot(input: &Target) -> Result<(), String> {
+        let shot = match env::var("DAY17_SHOT") {
+            Ok(shot) => shot,
+            Err(_) => return Ok(()),
+        };
+        let (x_vel, y_vel) = shot
+            .split(',')
+            .map(|v| v.trim().parse::<isize>())
+            .collect_tuple()
+            .and_then(|(x, y)| Some((x.ok()?, y.ok()?)))
+            .ok_or_else(|| format!("DAY17_SHOT: expected x,y, found {}", shot))?;
+        let t = trajectory(x_vel, y_vel, *input);
+        eprintln!("{}", t.plot());
+        eprintln!("{} on step {}", t.outcome, t.step());
+        if let Some(path) = env::var_os("DAY17_SVG") {
+            let mut f = File::create(path).map_err(|e| e.to_string())?;
+            t.write_svg(&mut f).map_err(|e| e.to_string())?;
         }
+        Ok(())
+    }
+
+    // Any x velocity beyond the far edge of the target overshoots on the first
+    // step, and likewise for y below the target. Upwards, a probe comes back
+    // through y=0 and then takes a step bigger than its launch velocity
+    fn velocities(
+        target: Target,
+    ) -> Result<(RangeInclusive<isize>, RangeInclusive<isize>), String> {
+        let ((x_min, x_max), (y_max, y_min)) = target;
+        let xs = cmp::min(x_min, 0)..=cmp::max(x_max, 0);
+        let y_hi = if y_min > 0 || y_max < 0 {
+            cmp::max(y_max, -y_min - 1)
+        } else {
+            let stalls = xs.clone().any(|v| {
+                let x = v.signum() * v.abs() * (v.abs() + 1) / 2;
+                x >= x_min && x <= x_max
+            });
+            if stalls {
+                return Err("infinitely many velocities hit a target spanning y=0".to_string());
+            }
+            // Without stalling, the probe leaves the target's columns within
+            // |x| steps, before a faster launch can fall back into range
+            let x_far = cmp::max(x_min.abs(), x_max.abs());
+            y_max + x_far * x_far / 2 + 1
+        };
+        Ok((xs, cmp::min(y_min, 0)..=y_hi))
+    }
+
+    fn tri(n: isize) -> isize {
+        n * (n + 1) / 2
     }
 
-    pub fn part_1(input: &Target) -> isize {
-        (1..=(input.0 .1))
-            .cartesian_product(1..=(input.1 .1 * -1))
-            .filter_map(|(xv, yv)| simulate(xv, yv, *input))
+    fn ceil_div(a: isize, b: isize) -> isize {
+        -(-a).div_euclid(b)
+    }
+
+    // Smallest n >= 0 with tri(n) >= x
+    fn tri_at_least(x: isize) -> isize {
+        let mut n = ((2 * cmp::max(x, 0)) as f64).sqrt() as isize;
+        while n > 0 && tri(n - 1) >= x {
+            n -= 1;
+        }
+        while tri(n) < x {
+            n += 1;
+        }
+        n
+    }
+
+    // Velocities v >= min_v reaching [lo, hi] (lo >= 0) on step k: either still
+    // moving, with v >= k, or already stalled at tri(v)
+    fn x_windows(lo: isize, hi: isize, min_v: isize, k: isize) -> Vec<RangeInclusive<isize>> {
+        let moving = cmp::max(ceil_div(lo + tri(k - 1), k), k)..=(hi + tri(k - 1)).div_euclid(k);
+        let stalled = cmp::max(tri_at_least(lo), min_v)..=cmp::min(tri_at_least(hi + 1) - 1, k - 1);
+        [moving, stalled]
+            .into_iter()
+            .filter(|r| !r.is_empty())
+            .collect()
+    }
+
+    type XRanges = Vec<RangeInclusive<isize>>;
+
+    fn merge(mut ranges: XRanges) -> XRanges {
+        ranges.sort_by_key(|r| *r.start());
+        let mut merged: Vec<RangeInclusive<isize>> = Vec::new();
+        for r in ranges {
+            match merged.last_mut() {
+                Some(last) if *r.start() <= last.end() + 1 => {
+                    *last = *last.start()..=cmp::max(*last.end(), *r.end());
+                }
+                _ => merged.push(r),
+            }
+        }
+        merged
+    }
+
+    // Highest point reached by the end of step k, for a probe that has not hit
+    // before then. Before the apex the probe is still climbing
+    fn height_by(yv: isize, k: isize) -> isize {
+        if yv <= 0 {
+            0
+        } else if k >= yv {
+            tri(yv)
+        } else {
+            k * yv - tri(k - 1)
+        }
+    }
+
+    // For every y velocity that can hit, the disjoint x velocity ranges that
+    // hit alongside it and the best height among those shots, each measured
+    // at its first hit
+    fn first_hits(target: &Target) -> Result<BTreeMap<isize, (XRanges, isize)>, String> {
+        let ((x_min, x_max), (y_max, y_min)) = *target;
+        // Rejects targets with infinitely many hits
+        velocities(*target)?;
+        let x_far = cmp::max(x_min.abs(), x_max.abs());
+        let y_far = cmp::max(y_min.abs(), y_max.abs());
+        let mut hits: BTreeMap<isize, (XRanges, isize)> = BTreeMap::new();
+        for k in 1..=2 * y_far + x_far + 1 {
+            let mut xs = Vec::new();
+            if x_max >= 0 {
+                xs.extend(x_windows(cmp::max(x_min, 0), x_max, 0, k));
+            }
+            if x_min < 0 {
+                let mirrored = x_windows(cmp::max(-x_max, 0), -x_min, 1, k);
+                xs.extend(mirrored.into_iter().map(|r| -r.end()..=-r.start()));
+            }
+            if xs.is_empty() {
+                continue;
+            }
+            for yv in ceil_div(y_min + tri(k - 1), k)..=(y_max + tri(k - 1)).div_euclid(k) {
+                let (seen, height) = hits.entry(yv).or_insert((Vec::new(), isize::MIN));
+                let new = xs.iter().any(|r| {
+                    !seen
+                        .iter()
+                        .any(|s| s.start() <= r.start() && r.end() <= s.end())
+                });
+                if new {
+                    *height = cmp::max(*height, height_by(yv, k));
+                    *seen = merge(seen.iter().chain(&xs).cloned().collect());
+                }
+            }
+        }
+        Ok(hits)
+    }
+
+    pub fn velocity_ranges(target: &Target) -> Result<BTreeMap<isize, XRanges>, String> {
+        Ok(first_hits(target)?
+            .into_iter()
+            .map(|(yv, (xs, _))| (yv, xs))
+            .collect())
+    }
+
+    #[allow(dead_code)]
+    pub fn hitting_velocities(target: &Target) -> Result<Vec<(isize, isize)>, String> {
+        Ok(velocity_ranges(target)?
+            .into_iter()
+            .flat_map(|(yv, xs)| xs.into_iter().flatten().map(move |xv| (xv, yv)))
+            .sorted()
+            .collect())
+    }
+
+    #[allow(dead_code)]
+    pub fn brute_force(target: &Target) -> Result<Vec<(isize, isize)>, String> {
+        let (xs, ys) = velocities(*target)?;
+        Ok(xs
+            .cartesian_product(ys)
+            .filter(|&(xv, yv)| simulate(xv, yv, *target).is_some())
+            .collect())
+    }
+
+    pub fn part_1(input: &Target) -> Result<isize, String> {
+        maybe_show_shot(input)?;
+        first_hits(input)?
+            .values()
+            .map(|&(_, height)| height)
             .max()
-            .unwrap()
+            .ok_or_else(|| "no velocity hits the target".to_string())
     }
 
-    pub fn part_2(input: &Target) -> isize {
-        (1..=(input.0 .1))
-            .cartesian_product(input.1 .1..=(input.1 .1 * -1))
-            .filter_map(|(xv, yv)| simulate(xv, yv, *input))
-            .count()
-            .try_into()
-            .unwrap()
+    pub fn part_2(input: &Target) -> Result<usize, String> {
+        Ok(velocity_ranges(input)?
+            .values()
+            .flatten()
+            .map(|r| (r.end() - r.start() + 1) as usize)
+            .sum())
     }
 }
 
@@ -65,6 +564,197 @@ mod tests {
     #[test]
     fn test_1() {
         let input = ((20, 30), (-5, -10));
-        assert_eq!(45, part_1(&input));
+        assert_eq!(Ok(45), part_1(&input));
+    }
+
+    #[test]
+    fn test_2() {
+        let input = ((20, 30), (-5, -10));
+        assert_eq!(Ok(112), part_2(&input));
+    }
+
+    #[test]
+    fn test_generator() {
+        assert_eq!(
+            Ok(((20, 30), (-5, -10))),
+            generator("target area: x=20..30, y=-10..-5\n")
+        );
+        assert_eq!(
+            Ok(((-30, -20), (10, 5))),
+            generator("target area: x=-20..-30, y=5..10")
+        );
+        assert!(generator("target area: x=20..30").is_err());
+    }
+
+    #[test]
+    fn test_quadrants() {
+        let mirrored = generator("target area: x=-30..-20, y=-10..-5").unwrap();
+        assert_eq!(Ok(45), part_1(&mirrored));
+        assert_eq!(Ok(112), part_2(&mirrored));
+        // Only (2, 2), (2, 3) and (3, 3) reach (3, 3)
+        let above = generator("target area: x=3..3, y=3..3").unwrap();
+        assert_eq!(Ok(6), part_1(&above));
+        assert_eq!(Ok(3), part_2(&above));
+        let straddle = generator("target area: x=-2..4, y=-3..-1").unwrap();
+        assert!(part_2(&straddle).unwrap() > 0);
+        // (22, 3) peaks at 6, while (100, 5) hits on the first step at 5
+        let high = generator("target area: x=100..100, y=5..5").unwrap();
+        assert_eq!(Ok(6), part_1(&high));
+        assert_eq!(Ok(2), part_2(&high));
+        let level = generator("target area: x=3..3, y=-1..1").unwrap();
+        assert!(part_2(&level).is_err());
+        // x=2 is only passed through on the first step
+        let passing = generator("target area: x=2..2, y=-1..1").unwrap();
+        assert_eq!(Ok(1), part_1(&passing));
+        assert_eq!(Ok(3), part_2(&passing));
+    }
+
+    #[test]
+    fn test_against_simulation() {
+        for x_min in (-15..=15).step_by(5).chain([40, 100]) {
+            for y_min in (-12..=8).step_by(4) {
+                for (w, h) in [(0, 0), (3, 2), (6, 5)] {
+                    let target = ((x_min, x_min + w), (y_min + h, y_min));
+                    match brute_force(&target) {
+                        Ok(expected) => {
+                            let best = expected
+                                .iter()
+                                .map(|&(xv, yv)| trajectory(xv, yv, target).max_height())
+                                .max();
+                            assert_eq!(Ok(expected), hitting_velocities(&target));
+                            assert_eq!(
+                                best.ok_or_else(|| "no velocity hits the target".to_string()),
+                                part_1(&target)
+                            );
+                        }
+                        Err(_) => assert!(part_2(&target).is_err()),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_far_target() {
+        let input = generator("target area: x=5000..6000, y=-6000..-5000").unwrap();
+        assert_eq!(Ok(17997000), part_1(&input));
+        // Every point of the target is hit on the first step
+        assert!(part_2(&input).unwrap() > 1001 * 1001);
+    }
+
+    #[test]
+    fn test_trajectory() {
+        let target = ((20, 30), (-5, -10));
+        let t = trajectory(7, 2, target);
+        assert_eq!(Outcome::Hit, t.outcome);
+        assert_eq!(7, t.step());
+        assert_eq!(
+            ".............#....#............
+.......#..............#........
+...............................
+S........................#.....
+...............................
+...............................
+...........................#...
+...............................
+....................TTTTTTTTTTT
+....................TTTTTTTTTTT
+....................TTTTTTTT#TT
+....................TTTTTTTTTTT
+....................TTTTTTTTTTT
+....................TTTTTTTTTTT",
+            t.plot()
+        );
+        let t = trajectory(17, -4, target);
+        assert_eq!((Outcome::OvershotX, 2), (t.outcome, t.step()));
+        assert_eq!(vec![(0, 0), (17, -4), (33, -9)], t.positions);
+        let t = trajectory(5, 3, target);
+        assert_eq!((Outcome::StalledShort, 5), (t.outcome, t.step()));
+        let t = trajectory(7, -10, target);
+        assert_eq!((Outcome::FellBelow, 2), (t.outcome, t.step()));
+        assert_eq!("fell below", t.outcome.to_string());
+    }
+
+    #[test]
+    fn test_svg() {
+        let t = trajectory(6, 3, ((20, 30), (-5, -10)));
+        let mut out = Vec::new();
+        t.write_svg(&mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(t.positions.len(), svg.matches("<circle").count());
+        assert!(svg.contains(r#"<rect x="19.5" y="4.5" width="11" height="6""#));
+    }
+
+    #[test]
+    fn test_physics() {
+        let physics = Physics {
+            drag: 2,
+            gravity: 2,
+            start: (5, 5),
+        };
+        let t = fly(&physics, 5, 3, &[((14, 14), (5, 5))], Mode::InOrder).unwrap();
+        assert_eq!(
+            vec![(5, 5), (10, 8), (13, 9), (14, 8), (14, 5)],
+            t.positions
+        );
+        assert_eq!((Outcome::Hit, vec![(0, 4)]), (t.outcome, t.hits));
+        let stuck = Physics {
+            gravity: 0,
+            ..Physics::default()
+        };
+        assert!(fly(&stuck, 1, 1, &[((14, 14), (5, 5))], Mode::Any).is_err());
+        // The default physics agree with the per-step solver
+        let target = ((20, 30), (-5, -10));
+        assert_eq!(
+            hitting_velocities(&target),
+            hits_any(&Physics::default(), &[target])
+        );
+        // Nothing stalls in column 2, so the launch height is no obstacle
+        let target = ((2, 2), (1, -1));
+        assert_eq!(
+            Ok(3),
+            hits_any(&Physics::default(), &[target]).map(|v| v.len())
+        );
+        for x_min in (-15..=15).step_by(5).chain([40, 100]) {
+            for y_min in (-12..=8).step_by(4) {
+                for (w, h) in [(0, 0), (3, 2), (6, 5)] {
+                    let target = ((x_min, x_min + w), (y_min + h, y_min));
+                    assert_eq!(
+                        hitting_velocities(&target).ok(),
+                        hits_any(&Physics::default(), &[target]).ok(),
+                        "{:?}",
+                        target
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_targets() {
+        let physics = Physics::default();
+        let near = ((5, 8), (-2, -4));
+        let far = ((14, 18), (-10, -16));
+        let all = hits_all(&physics, &[near, far]).unwrap();
+        assert!(!all.is_empty());
+        for &(xv, yv) in &all {
+            let t = fly(&physics, xv, yv, &[near, far], Mode::InOrder).unwrap();
+            assert_eq!(vec![0, 1], t.hits.iter().map(|h| h.0).collect::<Vec<_>>());
+            assert!(t.hits[0].1 < t.hits[1].1);
+        }
+        // The far target is never reached first and then the near one
+        assert_eq!(Ok(vec![]), hits_all(&physics, &[far, near]));
+        let mut either = hits_any(&physics, &[near])
+            .unwrap()
+            .into_iter()
+            .chain(hits_any(&physics, &[far]).unwrap())
+            .collect::<Vec<_>>();
+        either.sort_unstable();
+        either.dedup();
+        assert_eq!(Ok(either), hits_any(&physics, &[near, far]));
+        let t = fly(&physics, 1, 0, &[near, far], Mode::Any).unwrap();
+        assert_eq!(Outcome::StalledShort, t.outcome);
     }
 }
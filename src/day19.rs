@@ -1,55 +1,482 @@
-use itertools::Itertools;
+use itertools::{iproduct, Itertools};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::{fs, io::Error};
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, Write};
 
 type Point = (isize, isize, isize);
+// Per-axis distance between two readings to the index pairs that have it
+type PairsByDistance = HashMap<(isize, isize, isize), Vec<(usize, usize)>>;
 
 fn main() -> Result<(), Error> {
-    let s = fs::read_to_string("/Users/rahulrav/rust/aoc2021/data/day19.txt")?;
-    let input: Vec<Vec<Point>> = s.split("\n\n").map(parse_scanner).collect();
-    solve(input);
+    // day19 [--input=PATH] [--tolerance=N] [--edges] <out.ply>
+    // day19 --generate [--beacons=N] [--scanners=M] [--seed=S] <reports> <truth>
+    let args = env::args().skip(1).collect_vec();
+    let flag = |name: &str| args.iter().find_map(|a| a.strip_prefix(name));
+    let paths = args.iter().filter(|a| !a.starts_with("--")).collect_vec();
+    if args.iter().any(|a| a == "--generate") {
+        if paths.len() < 2 {
+            eprintln!(
+                "usage: day19 --generate [--beacons=N] [--scanners=M] [--seed=S] <reports> <truth>"
+            );
+            std::process::exit(2);
+        }
+        let mut config = GenConfig::default();
+        if let Some(n) = flag("--beacons=") {
+            config.beacons = n.parse().expect("invalid beacon count");
+        }
+        if let Some(m) = flag("--scanners=") {
+            config.scanners = m.parse().expect("invalid scanner count");
+        }
+        if let Some(seed) = flag("--seed=") {
+            config.seed = seed.parse().expect("invalid seed");
+        }
+        let (reports, truth) = generate(&config).expect("could not connect all scanners");
+        fs::write(paths[0], reports)?;
+        fs::write(paths[1], truth)?;
+        return Ok(());
+    }
+
+    let input = flag("--input=").unwrap_or("/Users/rahulrav/rust/aoc2021/data/day19.txt");
+    let s = fs::read_to_string(input)?;
+    let tolerance = flag("--tolerance=").map(|t| t.parse().expect("invalid tolerance"));
+    let map = match tolerance {
+        Some(tolerance) => solve_noisy(
+            parse(&s),
+            &NoisyConfig {
+                tolerance,
+                ..Default::default()
+            },
+        ),
+        None => solve(parse(&s)),
+    };
+    for (i, j) in &map.alignments {
+        println!("Scanner{} and Scanner{} intersect", i, j);
+    }
+    for i in map.unplaced() {
+        println!("Scanner{} could not be placed", i);
+    }
+    println!("Total num beacons={}", map.num_beacons());
+    println!("Max manhattan distance = {}", map.max_manhattan_distance());
+    if tolerance.is_some() {
+        let placed = map
+            .residuals
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| map.scanners[i].is_some());
+        for (i, r) in placed {
+            println!("Scanner{} residual = {:.3}", i, r);
+        }
+        for (i, p) in &map.outliers {
+            println!("Scanner{} outlier {:?}", i, p);
+        }
+    }
+
+    if let Some(path) = paths.first() {
+        let edges = args.iter().any(|a| a == "--edges");
+        map.write_ply(&mut BufWriter::new(File::create(path)?), edges)?;
+    }
     Ok(())
 }
 
-fn solve(mut input: Vec<Vec<Point>>) {
-    let mut scanner_infos = Vec::new();
-    let mut found_set = HashSet::new();
-    found_set.insert(0);
-    while found_set.len() != input.len() {
+#[derive(Debug)]
+struct ScannerMap {
+    // Indexed by scanner, all relative to scanner 0. None if the scanner
+    // never overlapped with any placed one
+    scanners: Vec<Option<ScannerInfo>>,
+    beacons: HashSet<Point>,
+    // (placed scanner, scanner aligned against it) in the order they were found
+    alignments: Vec<(usize, usize)>,
+    // RMS distance between matched readings when the scanner was aligned
+    residuals: Vec<f64>,
+    // (scanner, beacon) readings with no match in the reference scanner's range
+    outliers: Vec<(usize, Point)>,
+}
+
+impl ScannerMap {
+    fn unplaced(&self) -> Vec<usize> {
+        (0..self.scanners.len())
+            .filter(|&i| self.scanners[i].is_none())
+            .collect()
+    }
+
+    fn num_beacons(&self) -> usize {
+        self.beacons.len()
+    }
+
+    fn max_manhattan_distance(&self) -> isize {
+        self.scanners
+            .iter()
+            .flatten()
+            .tuple_combinations()
+            .map(|(s1, s2)| distance(s1.position, s2.position))
+            .map(|(x, y, z)| x + y + z)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // ASCII PLY point cloud: beacons in white, scanners in red and optionally
+    // the alignment tree as edges between scanners
+    fn write_ply<W: Write>(&self, w: &mut W, edges: bool) -> Result<(), Error> {
+        let beacons = self.beacons.iter().sorted().collect_vec();
+        let placed = self.scanners.iter().flatten().collect_vec();
+        let num_edges = if edges { self.alignments.len() } else { 0 };
+        writeln!(w, "ply")?;
+        writeln!(w, "format ascii 1.0")?;
+        writeln!(w, "element vertex {}", placed.len() + beacons.len())?;
+        writeln!(w, "property int x")?;
+        writeln!(w, "property int y")?;
+        writeln!(w, "property int z")?;
+        writeln!(w, "property uchar red")?;
+        writeln!(w, "property uchar green")?;
+        writeln!(w, "property uchar blue")?;
+        writeln!(w, "element edge {}", num_edges)?;
+        writeln!(w, "property int vertex1")?;
+        writeln!(w, "property int vertex2")?;
+        writeln!(w, "end_header")?;
+        for s in placed {
+            let (x, y, z) = s.position;
+            writeln!(w, "{} {} {} 255 0 0", x, y, z)?;
+        }
+        for (x, y, z) in beacons {
+            writeln!(w, "{} {} {} 255 255 255", x, y, z)?;
+        }
+        if edges {
+            // Placed scanners are numbered in order, skipping unplaced ones
+            let vertex = |i: usize| self.scanners[..i].iter().flatten().count();
+            for &(i, j) in &self.alignments {
+                writeln!(w, "{} {}", vertex(i), vertex(j))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn solve(input: Vec<Vec<Point>>) -> ScannerMap {
+    let fingerprints = input.iter().map(|s| fingerprint(s)).collect_vec();
+    reconstruct(input, 0, |i, j, _, s1, s2| {
+        if common_distances(&fingerprints[i], &fingerprints[j]) < MIN_COMMON_PAIRS {
+            return None;
+        }
+        scanner_pos(s1, s2).map(|info| Alignment {
+            info,
+            residual: 0.0,
+            outliers: Vec::new(),
+        })
+    })
+}
+
+#[derive(Debug)]
+struct NoisyConfig {
+    // Max per-axis disagreement between two readings of the same beacon
+    tolerance: isize,
+    min_overlap: usize,
+    range: isize,
+}
+
+impl Default for NoisyConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 2,
+            min_overlap: 12,
+            range: 1000,
+        }
+    }
+}
+
+fn solve_noisy(input: Vec<Vec<Point>>, config: &NoisyConfig) -> ScannerMap {
+    let fingerprints = input.iter().map(|s| noisy_fingerprint(s)).collect_vec();
+    // Each end of a pair can move by sqrt(3) * tolerance
+    let eps = 2.0 * 3f64.sqrt() * config.tolerance as f64;
+    let min_pairs = config.min_overlap * config.min_overlap.saturating_sub(1) / 2;
+    reconstruct(input, config.tolerance, |i, j, info, s1, s2| {
+        if common_distances_within(&fingerprints[i], &fingerprints[j], eps) < min_pairs {
+            return None;
+        }
+        consensus_align(s1, info.position, s2, config)
+    })
+}
+
+#[derive(Debug)]
+struct Alignment {
+    info: ScannerInfo,
+    residual: f64,
+    outliers: Vec<Point>,
+}
+
+fn reconstruct<F>(mut input: Vec<Vec<Point>>, tolerance: isize, align: F) -> ScannerMap
+where
+    F: Fn(usize, usize, &ScannerInfo, &[Point], &[Point]) -> Option<Alignment>,
+{
+    let mut scanners: Vec<Option<ScannerInfo>> = (0..input.len()).map(|_| None).collect();
+    scanners[0] = Some(ScannerInfo {
+        position: (0, 0, 0),
+        axis_order: (0, 1, 2),
+        axis_direction: (1, 1, 1),
+    });
+    let mut residuals = vec![0.0; input.len()];
+    let mut outliers = Vec::new();
+    let mut alignments = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(0);
+    while let Some(i) = queue.pop_front() {
         for scanner in 0..input.len() {
-            if found_set.contains(&scanner) {
+            if scanners[scanner].is_some() {
                 continue;
             }
-            let found_list = found_set.iter().copied().collect_vec();
-            for i in found_list {
-                if let Some(info) = scanner_pos(&input[i], &input[scanner]) {
-                    println!("Scanner{} and Scanner{} intersect", i, scanner);
-                    modify_beacons(&mut input[scanner], &info);
-                    found_set.insert(scanner);
-                    scanner_infos.push(info);
-                }
+            let info = scanners[i].as_ref().unwrap();
+            if let Some(al) = align(i, scanner, info, &input[i], &input[scanner]) {
+                modify_beacons(&mut input[scanner], &al.info);
+                scanners[scanner] = Some(al.info);
+                residuals[scanner] = al.residual;
+                outliers.extend(al.outliers.into_iter().map(|p| (scanner, p)));
+                alignments.push((i, scanner));
+                queue.push_back(scanner);
+            }
+        }
+    }
+    // Readings of the same beacon within the tolerance are merged. Unplaced
+    // scanners' readings are still in their own frame, so they are left out
+    let mut beacons = HashSet::new();
+    let placed = input
+        .into_iter()
+        .zip(&scanners)
+        .filter(|(_, s)| s.is_some());
+    for p in placed.flat_map(|(readings, _)| readings) {
+        if find_near(&beacons, p, tolerance).is_none() {
+            beacons.insert(p);
+        }
+    }
+
+    ScannerMap {
+        scanners,
+        beacons,
+        alignments,
+        residuals,
+        outliers,
+    }
+}
+
+fn find_near(points: &HashSet<Point>, p: Point, tolerance: isize) -> Option<Point> {
+    let r = -tolerance..=tolerance;
+    r.clone()
+        .cartesian_product(r.clone())
+        .cartesian_product(r)
+        .map(|((x, y), z)| (p.0 + x, p.1 + y, p.2 + z))
+        .find(|q| points.contains(q))
+}
+
+// The 24 orientations at the origin, reflections excluded
+fn rotations() -> Vec<ScannerInfo> {
+    let orders = [
+        ((0, 1, 2), 1),
+        ((1, 2, 0), 1),
+        ((2, 0, 1), 1),
+        ((0, 2, 1), -1),
+        ((1, 0, 2), -1),
+        ((2, 1, 0), -1),
+    ];
+    let mut rv = Vec::new();
+    for (order, parity) in orders {
+        for (x, y, z) in iproduct!([-1, 1], [-1, 1], [-1, 1]) {
+            if x * y * z == parity {
+                rv.push(ScannerInfo {
+                    position: (0, 0, 0),
+                    axis_order: order,
+                    axis_direction: (x, y, z),
+                });
             }
         }
     }
+    rv
+}
+
+// Every (rotation, beacon pair) hypothesis votes for a translation and the
+// most supported one wins. The transform is then refit on its inliers.
+fn consensus_align(
+    s1: &[Point],
+    s1_pos: Point,
+    s2: &[Point],
+    config: &NoisyConfig,
+) -> Option<Alignment> {
+    let tol = config.tolerance;
+    let mut best: Option<(usize, ScannerInfo)> = None;
+    for rot in rotations() {
+        let rotated = s2.iter().map(|&b| transform(b, &rot)).collect_vec();
+        // Translations from matching readings land in a block of 2x2x2 cells
+        let cell = 2 * tol + 1;
+        let mut votes: HashMap<Point, Vec<Point>> = HashMap::new();
+        for (a, r) in s1.iter().cartesian_product(rotated.iter()) {
+            let t = (a.0 - r.0, a.1 - r.1, a.2 - r.2);
+            let key = (
+                t.0.div_euclid(cell),
+                t.1.div_euclid(cell),
+                t.2.div_euclid(cell),
+            );
+            votes.entry(key).or_default().push(t);
+        }
+        for k in votes.keys() {
+            let block = || {
+                iproduct!(0..2, 0..2, 0..2)
+                    .filter_map(|(x, y, z)| votes.get(&(k.0 + x, k.1 + y, k.2 + z)))
+                    .flatten()
+            };
+            let support = block().count();
+            if best.as_ref().is_none_or(|(s, _)| support > *s) {
+                let median = |f: fn(&Point) -> isize| block().map(f).sorted().nth(support / 2);
+                best = Some((
+                    support,
+                    ScannerInfo {
+                        position: (
+                            median(|t| t.0).unwrap(),
+                            median(|t| t.1).unwrap(),
+                            median(|t| t.2).unwrap(),
+                        ),
+                        axis_order: rot.axis_order,
+                        axis_direction: rot.axis_direction,
+                    },
+                ));
+            }
+        }
+    }
+    let (_, mut info) = best?;
+
+    for _ in 0..2 {
+        let inliers = match_beacons(s1, s2, &info, tol);
+        if inliers.is_empty() {
+            return None;
+        }
+        let n = inliers.len() as f64;
+        let mean = |f: fn(&(Point, Point, usize)) -> isize| {
+            (inliers.iter().map(f).sum::<isize>() as f64 / n).round() as isize
+        };
+        let offset = (
+            mean(|(a, b, _)| a.0 - b.0),
+            mean(|(a, b, _)| a.1 - b.1),
+            mean(|(a, b, _)| a.2 - b.2),
+        );
+        info.position = (
+            info.position.0 + offset.0,
+            info.position.1 + offset.1,
+            info.position.2 + offset.2,
+        );
+    }
+
+    let inliers = match_beacons(s1, s2, &info, tol);
+    if inliers.len() < config.min_overlap {
+        return None;
+    }
+    let residual = (inliers
+        .iter()
+        .map(|(a, b, _)| {
+            let (x, y, z) = distance(*a, *b);
+            (x * x + y * y + z * z) as f64
+        })
+        .sum::<f64>()
+        / inliers.len() as f64)
+        .sqrt();
+    // Beacons the reference scanner should have seen but didn't
+    let matched: HashSet<usize> = inliers.iter().map(|(_, _, i)| *i).collect();
+    let outliers = s2
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !matched.contains(i))
+        .map(|(_, &b)| transform(b, &info))
+        .filter(|&p| {
+            let (x, y, z) = distance(p, s1_pos);
+            x <= config.range && y <= config.range && z <= config.range
+        })
+        .collect_vec();
+
+    Some(Alignment {
+        info,
+        residual,
+        outliers,
+    })
+}
+
+// (s1 beacon, transformed s2 beacon, s2 index) for every s2 beacon with an s1
+// beacon within the tolerance
+fn match_beacons(
+    s1: &[Point],
+    s2: &[Point],
+    info: &ScannerInfo,
+    tolerance: isize,
+) -> Vec<(Point, Point, usize)> {
+    s2.iter()
+        .enumerate()
+        .filter_map(|(i, &b)| {
+            let p = transform(b, info);
+            s1.iter()
+                .map(|&a| (a, distance(a, p)))
+                .filter(|(_, (x, y, z))| *x.max(y).max(z) <= tolerance)
+                .min_by_key(|(_, (x, y, z))| x + y + z)
+                .map(|(a, _)| (a, p, i))
+        })
+        .collect_vec()
+}
+
+const MIN_COMMON_BEACONS: usize = 12;
+// 12 common beacons give 12 * 11 / 2 common pairwise distances
+const MIN_COMMON_PAIRS: usize = 66;
+
+fn fingerprint(beacons: &[Point]) -> Vec<isize> {
+    beacons
+        .iter()
+        .tuple_combinations()
+        .map(|(p1, p2)| {
+            let (x, y, z) = distance(*p1, *p2);
+            x * x + y * y + z * z
+        })
+        .sorted()
+        .collect_vec()
+}
 
-    let mut beacon_set: HashSet<Point> = HashSet::new();
-    for scanner in input {
-        for b in scanner {
-            beacon_set.insert(b);
+fn common_distances(f1: &[isize], f2: &[isize]) -> usize {
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < f1.len() && j < f2.len() {
+        match f1[i].cmp(&f2[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
         }
     }
+    common
+}
 
-    println!("Total num beacons={}", beacon_set.len());
+fn noisy_fingerprint(beacons: &[Point]) -> Vec<f64> {
+    beacons
+        .iter()
+        .tuple_combinations()
+        .map(|(p1, p2)| {
+            let (x, y, z) = distance(*p1, *p2);
+            ((x * x + y * y + z * z) as f64).sqrt()
+        })
+        .sorted_by(|a, b| a.total_cmp(b))
+        .collect_vec()
+}
 
-    let m = (0..scanner_infos.len())
-        .cartesian_product(0..scanner_infos.len())
-        .filter(|(i, j)| i != j)
-        .map(|(i, j)| distance(scanner_infos[i].position, scanner_infos[j].position))
-        .map(|(x, y, z)| x + y + z)
-        .reduce(isize::max)
-        .unwrap();
-    println!("Max manhattan distance = {}", m);
+fn common_distances_within(f1: &[f64], f2: &[f64], eps: f64) -> usize {
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < f1.len() && j < f2.len() {
+        if (f1[i] - f2[j]).abs() <= eps {
+            common += 1;
+            i += 1;
+            j += 1;
+        } else if f1[i] < f2[j] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    common
 }
 
 fn distance(p1: Point, p2: Point) -> (isize, isize, isize) {
@@ -67,36 +494,51 @@ struct ScannerInfo {
     axis_direction: (isize, isize, isize),
 }
 
-fn modify_beacons(beacons: &mut Vec<Point>, info: &ScannerInfo) {
+fn modify_beacons(beacons: &mut [Point], info: &ScannerInfo) {
     for beacon in beacons.iter_mut() {
-        let reordered = reorder_axis(*beacon, info.axis_order);
-        let (x, y, z) = (
-            (info.position.0 + info.axis_direction.0 * reordered.0),
-            (info.position.1 + info.axis_direction.1 * reordered.1),
-            (info.position.2 + info.axis_direction.2 * reordered.2),
-        );
-        *beacon = (x, y, z);
+        *beacon = transform(*beacon, info);
     }
 }
 
+fn transform(beacon: Point, info: &ScannerInfo) -> Point {
+    let reordered = reorder_axis(beacon, info.axis_order);
+    (
+        (info.position.0 + info.axis_direction.0 * reordered.0),
+        (info.position.1 + info.axis_direction.1 * reordered.1),
+        (info.position.2 + info.axis_direction.2 * reordered.2),
+    )
+}
+
+fn inverse_transform(p: Point, info: &ScannerInfo) -> Point {
+    let d = [
+        info.axis_direction.0 * (p.0 - info.position.0),
+        info.axis_direction.1 * (p.1 - info.position.1),
+        info.axis_direction.2 * (p.2 - info.position.2),
+    ];
+    let mut rv = [0; 3];
+    rv[info.axis_order.0] = d[0];
+    rv[info.axis_order.1] = d[1];
+    rv[info.axis_order.2] = d[2];
+    (rv[0], rv[1], rv[2])
+}
+
 fn reorder_axis(p: Point, order: (usize, usize, usize)) -> Point {
     let tmp = [p.0, p.1, p.2];
     (tmp[order.0], tmp[order.1], tmp[order.2])
 }
 
-fn scanner_pos(s1: &Vec<Point>, s2: &Vec<Point>) -> Option<ScannerInfo> {
-    let mut p_to_dist: HashMap<(usize, usize), (isize, isize, isize)> = HashMap::new();
-    let mut dist_to_p: HashMap<(isize, isize, isize), (usize, usize)> = HashMap::new();
+fn scanner_pos(s1: &[Point], s2: &[Point]) -> Option<ScannerInfo> {
+    // Several pairs can share the same per-axis distances in dense scanners
+    let mut dist_to_p = PairsByDistance::new();
     for i in 0..s1.len() {
         for j in i + 1..s1.len() {
-            p_to_dist.insert((i, j), distance(s1[i], s1[j]));
-            p_to_dist.insert((j, i), distance(s1[i], s1[j]));
-            dist_to_p.insert(distance(s1[i], s1[j]), (i, j));
+            dist_to_p
+                .entry(distance(s1[i], s1[j]))
+                .or_default()
+                .push((i, j));
         }
     }
 
-    assert_eq!(p_to_dist.len(), dist_to_p.len() * 2);
-
     let order = vec![
         (0, 1, 2),
         (0, 2, 1),
@@ -130,29 +572,66 @@ fn scanner_pos(s1: &Vec<Point>, s2: &Vec<Point>) -> Option<ScannerInfo> {
         }
     }
 
-    if max_common != 66 {
+    if max_common < MIN_COMMON_PAIRS {
         return None;
     }
 
-    let mut iter = s2_common_pts.iter();
-    let p2_1 = reorder_axis(*iter.next().unwrap(), axis_order);
-    let p2_2 = reorder_axis(*iter.next().unwrap(), axis_order);
-    let p2_3 = reorder_axis(*iter.next().unwrap(), axis_order);
-    let d1 = distance(p2_1, p2_2);
-    let d2 = distance(p2_1, p2_3);
+    // Anchor on three common points and check the result, points sharing a
+    // coordinate can give the wrong axis directions
+    let s1_set: HashSet<Point> = s1.iter().copied().collect();
+    s2_common_pts
+        .iter()
+        .sorted()
+        .tuple_windows()
+        .flat_map(|(p1, p2, p3)| anchor(s1, &dist_to_p, axis_order, (*p1, *p2, *p3)))
+        .find(|info| {
+            s2.iter()
+                .filter(|&&p| s1_set.contains(&transform(p, info)))
+                .count()
+                >= MIN_COMMON_BEACONS
+        })
+}
+
+// Candidate placements from every pair of s1 pairs matching the anchor's
+// two distances
+fn anchor(
+    s1: &[Point],
+    dist_to_p: &PairsByDistance,
+    axis_order: (usize, usize, usize),
+    (p1, p2, p3): (Point, Point, Point),
+) -> Vec<ScannerInfo> {
+    let p2_1 = reorder_axis(p1, axis_order);
+    let p2_2 = reorder_axis(p2, axis_order);
+    let p2_3 = reorder_axis(p3, axis_order);
+    let (Some(d1), Some(d2)) = (
+        dist_to_p.get(&distance(p2_1, p2_2)),
+        dist_to_p.get(&distance(p2_1, p2_3)),
+    ) else {
+        return Vec::new();
+    };
+    d1.iter()
+        .cartesian_product(d2)
+        .filter_map(|(a, b)| anchor_pairs(s1, axis_order, (p2_1, p2_2), *a, *b))
+        .collect()
+}
 
-    let (p1_1, p1_2) = dist_to_p.get(&d1).unwrap();
-    let (p1_3, p1_4) = dist_to_p.get(&d2).unwrap();
+fn anchor_pairs(
+    s1: &[Point],
+    axis_order: (usize, usize, usize),
+    (p2_1, p2_2): (Point, Point),
+    (p1_1, p1_2): (usize, usize),
+    (p1_3, p1_4): (usize, usize),
+) -> Option<ScannerInfo> {
     let actual_p1_1;
     let actual_p1_2;
-    if *p1_1 == *p1_3 || *p1_1 == *p1_4 {
-        actual_p1_1 = s1[*p1_1];
-        actual_p1_2 = s1[*p1_2];
-    } else if *p1_2 == *p1_3 || *p1_2 == *p1_4 {
-        actual_p1_1 = s1[*p1_2];
-        actual_p1_2 = s1[*p1_1];
+    if p1_1 == p1_3 || p1_1 == p1_4 {
+        actual_p1_1 = s1[p1_1];
+        actual_p1_2 = s1[p1_2];
+    } else if p1_2 == p1_3 || p1_2 == p1_4 {
+        actual_p1_1 = s1[p1_2];
+        actual_p1_2 = s1[p1_1];
     } else {
-        panic!();
+        return None;
     }
 
     let x_dir = if p2_1.0.cmp(&p2_2.0) == actual_p1_1.0.cmp(&actual_p1_2.0) {
@@ -183,9 +662,522 @@ fn scanner_pos(s1: &Vec<Point>, s2: &Vec<Point>) -> Option<ScannerInfo> {
     Some(info)
 }
 
+#[derive(Debug)]
+struct GenConfig {
+    beacons: usize,
+    scanners: usize,
+    // Beacons are placed in a cube of this side centered on scanner 0
+    size: isize,
+    radius: isize,
+    min_overlap: usize,
+    seed: u64,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        Self {
+            beacons: 700,
+            scanners: 30,
+            size: 6000,
+            radius: 1000,
+            min_overlap: 12,
+            seed: 19,
+        }
+    }
+}
+
+// xorshift64*, enough for test data
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn range(&mut self, lo: isize, hi: isize) -> isize {
+        lo + (self.next() % (hi - lo + 1) as u64) as isize
+    }
+}
+
+// Returns the scanner reports and the ground truth, both in the format parse()
+// reads. The truth holds the scanner positions and then every detected beacon,
+// all relative to scanner 0. Each scanner shares at least min_overlap beacons
+// with an earlier one; None if that can't be arranged.
+fn generate(config: &GenConfig) -> Option<(String, String)> {
+    let mut rng = Rng::new(config.seed);
+    let half = config.size / 2;
+    let mut beacons = HashSet::new();
+    while beacons.len() < config.beacons {
+        beacons.insert((
+            rng.range(-half, half),
+            rng.range(-half, half),
+            rng.range(-half, half),
+        ));
+    }
+    let beacons = beacons.into_iter().sorted().collect_vec();
+    let detect = |pos: Point| -> Vec<Point> {
+        beacons
+            .iter()
+            .copied()
+            .filter(|&b| {
+                let (x, y, z) = distance(b, pos);
+                x <= config.radius && y <= config.radius && z <= config.radius
+            })
+            .collect_vec()
+    };
+
+    let rotations = rotations();
+    let mut scanners = vec![ScannerInfo {
+        position: (0, 0, 0),
+        axis_order: (0, 1, 2),
+        axis_direction: (1, 1, 1),
+    }];
+    let mut seen: Vec<HashSet<Point>> = vec![detect((0, 0, 0)).into_iter().collect()];
+    let mut tries = 0;
+    while scanners.len() < config.scanners {
+        tries += 1;
+        if tries > 1000 * config.scanners {
+            return None;
+        }
+        let near = &scanners[rng.next() as usize % scanners.len()];
+        let r = config.radius;
+        let position = (
+            near.position.0 + rng.range(-r, r),
+            near.position.1 + rng.range(-r, r),
+            near.position.2 + rng.range(-r, r),
+        );
+        let visible: HashSet<Point> = detect(position).into_iter().collect();
+        if seen
+            .iter()
+            .all(|s| s.intersection(&visible).count() < config.min_overlap)
+        {
+            continue;
+        }
+        let rot = &rotations[rng.next() as usize % rotations.len()];
+        scanners.push(ScannerInfo {
+            position,
+            axis_order: rot.axis_order,
+            axis_direction: rot.axis_direction,
+        });
+        seen.push(visible);
+    }
+
+    let mut reports = Vec::new();
+    for (k, info) in scanners.iter().enumerate() {
+        let mut report = format!("--- scanner {} ---\n", k);
+        for b in detect(info.position) {
+            let (x, y, z) = inverse_transform(b, info);
+            report += &format!("{},{},{}\n", x, y, z);
+        }
+        reports.push(report);
+    }
+    let mut truth = String::from("--- scanner positions ---\n");
+    for info in &scanners {
+        let (x, y, z) = info.position;
+        truth += &format!("{},{},{}\n", x, y, z);
+    }
+    truth += "\n--- beacons ---\n";
+    for (x, y, z) in seen
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .sorted()
+    {
+        truth += &format!("{},{},{}\n", x, y, z);
+    }
+    Some((reports.join("\n"), truth))
+}
+
+fn parse(s: &str) -> Vec<Vec<Point>> {
+    s.split("\n\n").map(parse_scanner).collect()
+}
+
 fn parse_scanner(s: &str) -> Vec<Point> {
     s.lines()
         .skip(1)
         .filter_map(|l| l.split(',').filter_map(|n| n.parse().ok()).collect_tuple())
         .collect_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_INPUT: &str = "--- scanner 0 ---
+404,-588,-901
+528,-643,409
+-838,591,734
+390,-675,-793
+-537,-823,-458
+-485,-357,347
+-345,-311,381
+-661,-816,-575
+-876,649,763
+-618,-824,-621
+553,345,-567
+474,580,667
+-447,-329,318
+-584,868,-557
+544,-627,-890
+564,392,-477
+455,729,728
+-892,524,684
+-689,845,-530
+423,-701,434
+7,-33,-71
+630,319,-379
+443,580,662
+-789,900,-551
+459,-707,401
+
+--- scanner 1 ---
+686,422,578
+605,423,415
+515,917,-361
+-336,658,858
+95,138,22
+-476,619,847
+-340,-569,-846
+567,-361,727
+-460,603,-452
+669,-402,600
+729,430,532
+-500,-761,534
+-322,571,750
+-466,-666,-811
+-429,-592,574
+-355,545,-477
+703,-491,-529
+-328,-685,520
+413,935,-424
+-391,539,-444
+586,-435,557
+-364,-763,-893
+807,-499,-711
+755,-354,-619
+553,889,-390
+
+--- scanner 2 ---
+649,640,665
+682,-795,504
+-784,533,-524
+-644,584,-595
+-588,-843,648
+-30,6,44
+-674,560,763
+500,723,-460
+609,671,-379
+-555,-800,653
+-675,-892,-343
+697,-426,-610
+578,704,681
+493,664,-388
+-671,-858,530
+-667,343,800
+571,-461,-707
+-138,-166,112
+-889,563,-600
+646,-828,498
+640,759,510
+-630,509,768
+-681,-892,-333
+673,-379,-804
+-742,-814,-386
+577,-820,562
+
+--- scanner 3 ---
+-589,542,597
+605,-692,669
+-500,565,-823
+-660,373,557
+-458,-679,-417
+-488,449,543
+-626,468,-788
+338,-750,-386
+528,-832,-391
+562,-778,733
+-938,-730,414
+543,643,-506
+-524,371,-870
+407,773,750
+-104,29,83
+378,-903,-323
+-778,-728,485
+426,699,580
+-438,-605,-362
+-469,-447,-387
+509,732,623
+647,635,-688
+-868,-804,481
+614,-800,639
+595,780,-596
+
+--- scanner 4 ---
+727,592,562
+-293,-554,779
+441,611,-461
+-714,465,-776
+-743,427,-804
+-660,-479,-426
+832,-632,460
+927,-485,-438
+408,393,-506
+466,436,-512
+110,16,151
+-258,-428,682
+-393,719,612
+-211,-452,876
+808,-476,-593
+-575,615,604
+-485,667,467
+-680,325,-822
+-627,-443,-432
+872,-547,-609
+833,512,582
+807,604,487
+839,-516,451
+891,-625,532
+-652,-548,-490
+30,-46,-14";
+
+    #[test]
+    fn test_scanner_positions() {
+        let map = solve(parse(TEST_INPUT));
+        let positions = map
+            .scanners
+            .iter()
+            .flatten()
+            .map(|s| s.position)
+            .collect_vec();
+        assert_eq!(
+            vec![
+                (0, 0, 0),
+                (68, -1246, -43),
+                (1105, -1205, 1229),
+                (-92, -2380, -20),
+                (-20, -1133, 1061)
+            ],
+            positions
+        );
+        assert_eq!(4, map.alignments.len());
+        assert!(map.alignments.contains(&(0, 1)));
+        assert!(map.alignments.contains(&(1, 4)));
+    }
+
+    #[test]
+    fn test_part_1() {
+        let map = solve(parse(TEST_INPUT));
+        assert_eq!(79, map.num_beacons());
+        assert!(map.beacons.contains(&(-892, 524, 684)));
+        assert!(map.beacons.contains(&(1889, -1729, 1762)));
+    }
+
+    #[test]
+    fn test_write_ply() {
+        let map = solve(parse(TEST_INPUT));
+        let mut out = Vec::new();
+        map.write_ply(&mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect_vec();
+        assert!(lines.contains(&"element vertex 84"));
+        assert!(lines.contains(&"element edge 4"));
+        let body = &lines[lines.iter().position(|&l| l == "end_header").unwrap() + 1..];
+        assert_eq!(84 + 4, body.len());
+        assert_eq!("68 -1246 -43 255 0 0", body[1]);
+        assert_eq!("-892 524 684 255 255 255", body[5]);
+        assert!(body[84..].contains(&"0 1"));
+
+        let mut out = Vec::new();
+        map.write_ply(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("element edge 0\n"));
+        assert_eq!(
+            84,
+            out.lines().skip_while(|&l| l != "end_header").count() - 1
+        );
+    }
+
+    // Readings off by up to 1 on x and y, scanner 2 misses a beacon that
+    // scanner 4 also sees and scanner 1 reports a spurious one at (0, -500, 0)
+    fn noisy_input() -> Vec<Vec<Point>> {
+        let mut input = parse(TEST_INPUT);
+        for (k, scanner) in input.iter_mut().enumerate() {
+            for (n, b) in scanner.iter_mut().enumerate() {
+                b.0 += ((n * 7 + k) % 3) as isize - 1;
+                b.1 += ((n * 5 + k * 2) % 3) as isize - 1;
+            }
+        }
+        input[2].remove(0);
+        input[1].push((68, 746, -43));
+        input
+    }
+
+    #[test]
+    fn test_noisy_exact_fails() {
+        let input = noisy_input();
+        let fingerprints = input.iter().map(|s| fingerprint(s)).collect_vec();
+        assert!(common_distances(&fingerprints[0], &fingerprints[1]) < MIN_COMMON_PAIRS);
+    }
+
+    #[test]
+    fn test_noisy() {
+        let config = NoisyConfig {
+            min_overlap: 10,
+            ..Default::default()
+        };
+        let map = solve_noisy(noisy_input(), &config);
+        let expected = [
+            (0, 0, 0),
+            (68, -1246, -43),
+            (1105, -1205, 1229),
+            (-92, -2380, -20),
+            (-20, -1133, 1061),
+        ];
+        assert!(map.unplaced().is_empty());
+        for (s, e) in map.scanners.iter().flatten().zip(expected) {
+            let (x, y, z) = distance(s.position, e);
+            assert!(x.max(y).max(z) <= config.tolerance, "{:?}", s);
+        }
+        assert_eq!(0.0, map.residuals[0]);
+        assert!(map.residuals[1..].iter().all(|&r| r > 0.0 && r < 2.0));
+        assert_eq!(80, map.num_beacons());
+        assert!(map.outliers.iter().any(|&(i, p)| {
+            let (x, y, z) = distance(p, (0, -500, 0));
+            i == 1 && x.max(y).max(z) <= config.tolerance
+        }));
+    }
+
+    #[test]
+    fn test_noisy_no_noise() {
+        let map = solve_noisy(parse(TEST_INPUT), &NoisyConfig::default());
+        assert_eq!(79, map.num_beacons());
+        assert_eq!(3621, map.max_manhattan_distance());
+        assert!(map.residuals.iter().all(|&r| r == 0.0));
+        assert!(map.outliers.is_empty());
+    }
+
+    #[test]
+    fn test_inverse_transform() {
+        for rot in rotations() {
+            let info = ScannerInfo {
+                position: (5, -7, 11),
+                ..rot
+            };
+            let p = (1, 20, -300);
+            assert_eq!(p, transform(inverse_transform(p, &info), &info));
+        }
+    }
+
+    #[test]
+    fn test_dense_scanners() {
+        let config = GenConfig {
+            beacons: 400,
+            scanners: 3,
+            size: 600,
+            seed: 5,
+            ..Default::default()
+        };
+        let (reports, truth) = generate(&config).unwrap();
+        let (reports, truth) = (parse(&reports), parse(&truth));
+        // Some pairs in scanner 0 share their per-axis distances
+        let n = reports[0].len();
+        let pairs = n * (n - 1) / 2;
+        let distinct = reports[0]
+            .iter()
+            .tuple_combinations()
+            .map(|(&a, &b)| distance(a, b))
+            .unique()
+            .count();
+        assert!(distinct < pairs);
+        let map = solve(reports);
+        assert!(map.unplaced().is_empty());
+        let positions = map
+            .scanners
+            .iter()
+            .flatten()
+            .map(|s| s.position)
+            .collect_vec();
+        assert_eq!(truth[0], positions);
+        assert_eq!(truth[1].len(), map.num_beacons());
+    }
+
+    #[test]
+    fn test_generate() {
+        let config = GenConfig {
+            beacons: 120,
+            scanners: 8,
+            size: 3000,
+            ..Default::default()
+        };
+        let (reports, truth) = generate(&config).unwrap();
+        let truth = parse(&truth);
+        let map = solve(parse(&reports));
+        let positions = map
+            .scanners
+            .iter()
+            .flatten()
+            .map(|s| s.position)
+            .collect_vec();
+        assert_eq!(truth[0], positions);
+        assert_eq!(truth[1].len(), map.num_beacons());
+        assert!(truth[1].iter().all(|b| map.beacons.contains(b)));
+
+        let map = solve_noisy(
+            parse(&reports),
+            &NoisyConfig {
+                tolerance: 1,
+                ..Default::default()
+            },
+        );
+        let positions = map
+            .scanners
+            .iter()
+            .flatten()
+            .map(|s| s.position)
+            .collect_vec();
+        assert_eq!(truth[0], positions);
+    }
+
+    #[test]
+    fn test_part_2() {
+        let map = solve(parse(TEST_INPUT));
+        assert_eq!(3621, map.max_manhattan_distance());
+    }
+
+    #[test]
+    fn test_unplaced_scanner() {
+        let lonely = (1..=15)
+            .map(|i| (i * 37, i * i * 11, -i * 53))
+            .collect_vec();
+        let mut input = parse(TEST_INPUT);
+        input.insert(2, lonely.clone());
+        let map = solve(input);
+        assert_eq!(vec![2], map.unplaced());
+        assert_eq!(79, map.num_beacons());
+        assert_eq!(3621, map.max_manhattan_distance());
+        let mut out = Vec::new();
+        map.write_ply(&mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("element vertex 84\n"));
+        // Scanner 5 is the fifth vertex, after 0, 1, 3 and 4
+        assert!(out.lines().any(|l| l == "1 4" || l == "4 1"));
+
+        let mut input = noisy_input();
+        input.push(lonely);
+        let config = NoisyConfig {
+            min_overlap: 10,
+            ..Default::default()
+        };
+        let map = solve_noisy(input, &config);
+        assert_eq!(vec![5], map.unplaced());
+    }
+}